@@ -0,0 +1,408 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::commands::create::ROOT_DIR_NAME;
+use crate::crun::{crun_exec_output, crun_state_bundle};
+use crate::util::{remove_dir_all_if_exists, PathExt, Rollback};
+
+/// Back up the disks of a running container's VM, without stopping it.
+#[derive(clap::Args, Debug)]
+pub struct Backup {
+    /// Only save what changed since the previous backup. Disks that aren't in qcow2 format are
+    /// still backed up in full.
+    #[clap(long)]
+    incremental: bool,
+
+    /// Directory to move the backup into, instead of keeping it in the container's bundle.
+    #[clap(long)]
+    output_dir: Option<PathBuf>,
+
+    container_id: String,
+}
+
+/// Where in the container backups are written, each in a subdirectory named after the backup.
+const BACKUPS_DIR_PATH_IN_CONTAINER: &str = "crun-vm/backups";
+
+struct Disk {
+    /// Target device name, *e.g.*, `vda`.
+    name: String,
+    /// Whether the disk is backed up, which it isn't if it is a CD-ROM or the first-boot config
+    /// disk that crun-vm generates.
+    backed_up: bool,
+    /// Whether the disk is in qcow2 format and can thus track changes with dirty bitmaps.
+    is_qcow2: bool,
+}
+
+pub fn backup(global_args: &liboci_cli::GlobalOpts, args: &Backup) -> Result<()> {
+    let bundle_path = crun_state_bundle(global_args, &args.container_id)
+        .with_context(|| format!("container {} doesn't exist", args.container_id))?;
+    let root_path = bundle_path.join(ROOT_DIR_NAME);
+
+    let disks = get_disks(&root_path.join("crun-vm/domain.xml"))?;
+    ensure!(
+        disks.iter().any(|d| d.backed_up),
+        "the VM has no disks to back up"
+    );
+
+    let virsh = |command: &[&str]| {
+        let command: Vec<&str> = ["/crun-vm/virsh", "--quiet"]
+            .iter()
+            .chain(command)
+            .copied()
+            .collect();
+        crun_exec_output(global_args, &args.container_id, &command)
+    };
+
+    // Every backup creates a checkpoint, which incremental backups are then relative to. Only the
+    // latest one is kept, as each one has libvirt track changes to the disks from then on.
+    let previous_checkpoint = virsh(&["checkpoint-list", "domain", "--name", "--topological"])?
+        .lines()
+        .rfind(|l| !l.trim().is_empty())
+        .map(|l| l.trim().to_string());
+
+    ensure!(
+        !args.incremental || previous_checkpoint.is_some(),
+        "there is no previous backup to base an incremental one on"
+    );
+    let incremental_base = previous_checkpoint.as_deref().filter(|_| args.incremental);
+
+    let name = format!(
+        "backup-{}",
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+    );
+    let backup_dir_path_in_container = Path::new("/")
+        .join(BACKUPS_DIR_PATH_IN_CONTAINER)
+        .join(&name);
+    let backup_dir_path_in_host = root_path.join(BACKUPS_DIR_PATH_IN_CONTAINER).join(&name);
+
+    // names only have a resolution of one second
+    let already_exists = || anyhow!("backup {name} already exists; try again in a second");
+
+    if let Some(output_dir) = &args.output_dir {
+        ensure!(!output_dir.join(&name).try_exists()?, already_exists());
+    }
+
+    // don't leave a partial backup behind if anything below fails
+    let mut rollback = Rollback::default();
+    fs::create_dir_all(backup_dir_path_in_host.parent().unwrap())?;
+    fs::create_dir(&backup_dir_path_in_host).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => already_exists(),
+        _ => e.into(),
+    })?;
+    rollback.push({
+        let path = backup_dir_path_in_host.clone();
+        move || remove_dir_all_if_exists(path)
+    });
+
+    // write backup and checkpoint definitions
+
+    let backup_xml_path = format!("crun-vm/{name}-backup.xml");
+    fs::write(
+        root_path.join(&backup_xml_path),
+        backup_xml(&disks, &backup_dir_path_in_container, incremental_base),
+    )?;
+
+    let checkpoint_xml_path = format!("crun-vm/{name}-checkpoint.xml");
+    let has_checkpoint = disks.iter().any(|d| d.backed_up && d.is_qcow2);
+    if has_checkpoint {
+        fs::write(
+            root_path.join(&checkpoint_xml_path),
+            checkpoint_xml(&disks, &name),
+        )?;
+    }
+
+    // Start the backup job. Its point in time is when it starts, so we only need to keep guest file
+    // systems frozen until then.
+
+    let frozen = virsh(&["domfsfreeze", "domain"]).is_ok();
+    if !frozen {
+        eprintln!(
+            "warning: couldn't freeze guest file systems, is the QEMU guest agent running in the \
+            VM? The backup will only be crash-consistent."
+        );
+    }
+
+    let thaw_guard = frozen.then(|| FsThawGuard { virsh: &virsh });
+
+    let backup_xml_path_in_container = format!("/{backup_xml_path}");
+    let checkpoint_xml_path_in_container = format!("/{checkpoint_xml_path}");
+    let mut backup_begin = vec!["backup-begin", "domain", &backup_xml_path_in_container];
+    if has_checkpoint {
+        backup_begin.push(&checkpoint_xml_path_in_container);
+    }
+
+    let result = virsh(&backup_begin);
+
+    drop(thaw_guard);
+
+    fs::remove_file(root_path.join(&backup_xml_path))?;
+    if has_checkpoint {
+        fs::remove_file(root_path.join(&checkpoint_xml_path))?;
+    }
+
+    result?;
+
+    // wait for the backup job to finish
+
+    let result = (|| {
+        while get_job_type(&virsh(&["domjobinfo", "domain"])?)? != "None" {
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        let job_type = get_job_type(&virsh(&["domjobinfo", "domain", "--completed"])?)?;
+        if job_type != "Completed" {
+            bail!("the backup job failed ({job_type})");
+        }
+
+        Ok(())
+    })();
+
+    // the checkpoint was created when the job started, but incremental backups must not be
+    // relative to a failed one
+    if result.is_err() && has_checkpoint {
+        if let Err(e) = virsh(&["checkpoint-delete", "domain", &name]) {
+            eprintln!("warning: failed to delete checkpoint {name}: {e:#}");
+        }
+    }
+
+    result?;
+
+    // move backup to its final location
+
+    let backup_path = match &args.output_dir {
+        Some(output_dir) => {
+            fs::create_dir_all(output_dir)?;
+
+            let status = Command::new("mv")
+                .arg("--no-target-directory")
+                .arg(&backup_dir_path_in_host)
+                .arg(output_dir.join(&name))
+                .spawn()?
+                .wait()?;
+
+            ensure!(status.success(), "`mv` failed");
+
+            output_dir.join(&name)
+        }
+        None => backup_dir_path_in_host,
+    };
+
+    rollback.commit();
+
+    // the new checkpoint tracks changes from now on, so the previous one is no longer needed
+    if let (Some(checkpoint), true) = (&previous_checkpoint, has_checkpoint) {
+        if let Err(e) = virsh(&["checkpoint-delete", "domain", checkpoint]) {
+            eprintln!("warning: failed to delete previous checkpoint {checkpoint}: {e:#}");
+        }
+    }
+
+    println!("{}", backup_path.as_str());
+
+    Ok(())
+}
+
+/// Thaws the guest file systems when dropped, so that they are thawed even if starting the backup
+/// fails.
+struct FsThawGuard<'a> {
+    virsh: &'a dyn Fn(&[&str]) -> Result<String>,
+}
+
+impl Drop for FsThawGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = (self.virsh)(&["domfsthaw", "domain"]) {
+            eprintln!("warning: failed to thaw guest file systems, which may remain frozen: {e:#}");
+        }
+    }
+}
+
+/// Where crun-vm puts the cloud-init config disk it generates, among other first-boot files.
+const FIRST_BOOT_DIR_PATH_IN_CONTAINER: &str = "/crun-vm/first-boot";
+
+/// Returns the disks of the VM, as given in its libvirt domain XML.
+fn get_disks(domain_xml_path: &Path) -> Result<Vec<Disk>> {
+    let reader = BufReader::new(File::open(domain_xml_path)?);
+    let domain = minidom::Element::from_reader_with_prefixes(reader, "".to_string())?;
+    parse_disks(&domain)
+}
+
+fn parse_disks(domain: &minidom::Element) -> Result<Vec<Disk>> {
+    let devices = domain
+        .get_child("devices", "")
+        .ok_or_else(|| anyhow!("the domain XML has no devices"))?;
+
+    devices
+        .children()
+        .filter(|e| e.name() == "disk")
+        .map(|disk| {
+            let name = disk
+                .get_child("target", "")
+                .and_then(|t| t.attr("dev"))
+                .ok_or_else(|| anyhow!("the domain XML has a disk without a target device"))?;
+
+            let is_first_boot_config = disk
+                .get_child("source", "")
+                .and_then(|s| s.attr("file"))
+                .is_some_and(|f| Path::new(f).starts_with(FIRST_BOOT_DIR_PATH_IN_CONTAINER));
+
+            Ok(Disk {
+                name: name.to_string(),
+                backed_up: disk.attr("device").unwrap_or("disk") == "disk" && !is_first_boot_config,
+                is_qcow2: disk.get_child("driver", "").and_then(|d| d.attr("type"))
+                    == Some("qcow2"),
+            })
+        })
+        .collect()
+}
+
+fn backup_xml(disks: &[Disk], dir_path: &Path, previous_checkpoint: Option<&str>) -> String {
+    let mut xml = "<domainbackup mode='push'>\n".to_string();
+
+    if let Some(checkpoint) = previous_checkpoint {
+        xml += &format!("  <incremental>{checkpoint}</incremental>\n");
+    }
+
+    xml += "  <disks>\n";
+
+    for disk in disks {
+        if disk.backed_up {
+            // disks without dirty bitmaps can only be backed up in full
+            let mode = if disk.is_qcow2 || previous_checkpoint.is_none() {
+                ""
+            } else {
+                " backupmode='full'"
+            };
+
+            xml += &format!(
+                "    <disk name='{name}' backup='yes' type='file'{mode}>\n      \
+                <target file='{path}'/>\n      \
+                <driver type='qcow2'/>\n    \
+                </disk>\n",
+                name = disk.name,
+                path = dir_path.join(format!("{}.qcow2", disk.name)).as_str(),
+            );
+        } else {
+            xml += &format!("    <disk name='{}' backup='no'/>\n", disk.name);
+        }
+    }
+
+    xml += "  </disks>\n</domainbackup>\n";
+    xml
+}
+
+fn checkpoint_xml(disks: &[Disk], name: &str) -> String {
+    let mut xml = format!("<domaincheckpoint>\n  <name>{name}</name>\n  <disks>\n");
+
+    for disk in disks {
+        let checkpoint = if disk.backed_up && disk.is_qcow2 {
+            "bitmap"
+        } else {
+            "no"
+        };
+        xml += &format!(
+            "    <disk name='{}' checkpoint='{checkpoint}'/>\n",
+            disk.name
+        );
+    }
+
+    xml += "  </disks>\n</domaincheckpoint>\n";
+    xml
+}
+
+/// Extracts the job type from the output of `virsh domjobinfo`.
+fn get_job_type(job_info: &str) -> Result<String> {
+    job_info
+        .lines()
+        .find_map(|l| l.strip_prefix("Job type:"))
+        .map(|t| t.trim().to_string())
+        .ok_or_else(|| anyhow!("unexpected output from `virsh domjobinfo`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disks() -> Vec<Disk> {
+        vec![
+            Disk {
+                name: "vda".to_string(),
+                backed_up: true,
+                is_qcow2: true,
+            },
+            Disk {
+                name: "vdb".to_string(),
+                backed_up: true,
+                is_qcow2: false,
+            },
+            Disk {
+                name: "sda".to_string(),
+                backed_up: false,
+                is_qcow2: false,
+            },
+            Disk {
+                name: "vdc".to_string(),
+                backed_up: false,
+                is_qcow2: false,
+            },
+        ]
+    }
+
+    #[test]
+    fn disks_skip_cdroms_and_first_boot_config() {
+        let xml = "<domain><devices>\
+            <disk type='file' device='disk'><driver type='qcow2'/>\
+                <source file='/crun-vm/image-overlay.qcow2'/><target dev='vda'/></disk>\
+            <disk type='file' device='cdrom'><driver type='raw'/>\
+                <source file='/crun-vm/image/installer.iso'/><target dev='sda'/></disk>\
+            <disk type='file' device='disk'>\
+                <source file='/crun-vm/first-boot/cloud-init.iso'/><target dev='vdb'/></disk>\
+            </devices></domain>";
+        let domain =
+            minidom::Element::from_reader_with_prefixes(xml.as_bytes(), "".to_string()).unwrap();
+
+        let disks = parse_disks(&domain).unwrap();
+        let backed_up: Vec<_> = disks
+            .iter()
+            .map(|d| (d.name.as_str(), d.backed_up))
+            .collect();
+
+        assert_eq!(backed_up, [("vda", true), ("sda", false), ("vdb", false)]);
+        assert!(disks[0].is_qcow2);
+    }
+
+    #[test]
+    fn full_backup_xml() {
+        let xml = backup_xml(&disks(), Path::new("/backups/b"), None);
+
+        assert!(!xml.contains("<incremental>"));
+        assert!(!xml.contains("backupmode"));
+        assert!(xml.contains("<target file='/backups/b/vda.qcow2'/>"));
+        assert!(xml.contains("<disk name='sda' backup='no'/>"));
+        assert!(xml.contains("<disk name='vdc' backup='no'/>"));
+    }
+
+    #[test]
+    fn incremental_backup_xml() {
+        let xml = backup_xml(&disks(), Path::new("/backups/b"), Some("backup-1"));
+
+        assert!(xml.contains("<incremental>backup-1</incremental>"));
+        assert!(xml.contains("<disk name='vda' backup='yes' type='file'>"));
+        assert!(xml.contains("<disk name='vdb' backup='yes' type='file' backupmode='full'>"));
+        assert!(xml.contains("<disk name='vdc' backup='no'/>"));
+        assert!(!xml.contains("vdc.qcow2"));
+    }
+
+    #[test]
+    fn job_type() {
+        let job_info = "Job type:         Completed\nOperation:        Backup\n";
+        assert_eq!(get_job_type(job_info).unwrap(), "Completed");
+        assert!(get_job_type("").is_err());
+    }
+}
@@ -0,0 +1,366 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde_json::json;
+
+use crate::commands::create::{
+    VmImageOverlay, VmImageOverlays, ROOT_DIR_NAME, VM_IMAGE_OVERLAYS_FILE_PATH_IN_CONTAINER,
+};
+use crate::util::{
+    compute_file_digest, get_qcow2_backing_file, remove_dir_all_if_exists, try_lock_file, PathExt,
+    Rollback,
+};
+
+/// Save the changes that a stopped container's VM made to its disks as a new VM image.
+#[derive(clap::Args, Debug)]
+pub struct Commit {
+    /// Instead of flattening the changes and the original VM image into a standalone VM image,
+    /// save only the changes, in a qcow2 file backed by the original VM image file. With
+    /// --oci-layout, the original VM image files and the changes go in separate layers.
+    #[clap(long)]
+    rebase: bool,
+
+    /// Write the VM image to this file.
+    #[clap(
+        long,
+        required_unless_present = "oci_layout",
+        conflicts_with = "oci_layout"
+    )]
+    disk: Option<PathBuf>,
+
+    /// Write the VM image as a container image in an OCI image layout in this directory.
+    #[clap(long)]
+    oci_layout: Option<PathBuf>,
+
+    /// Bundle directory of the container.
+    bundle: PathBuf,
+}
+
+pub fn commit(args: &Commit) -> Result<()> {
+    let overlays_file_path = args
+        .bundle
+        .join(ROOT_DIR_NAME)
+        .join(VM_IMAGE_OVERLAYS_FILE_PATH_IN_CONTAINER);
+
+    let overlays: VmImageOverlays = match File::open(&overlays_file_path) {
+        Ok(file) => serde_json::from_reader(file)?,
+        Err(_) => bail!(
+            "{} has no VM image overlays to commit; was it created by crun-vm, and without \
+            --persistent or an installer ISO image?",
+            args.bundle.as_str()
+        ),
+    };
+
+    // the container entrypoint holds this lock while the VM runs
+    let _lock = try_lock_file(&overlays.lock_path)?
+        .context("the container is still running; stop it before committing")?;
+
+    for overlay in &overlays.overlays {
+        ensure!(
+            !overlay.encrypted,
+            "can't commit encrypted VM images or overlays"
+        );
+        ensure!(
+            overlay.base_path.try_exists()?,
+            "VM image {} is no longer accessible; if the container's root file system was \
+            unmounted, mount it again, *e.g.*, with `podman mount`",
+            overlay.base_path.as_str()
+        );
+    }
+
+    match (&args.disk, &args.oci_layout) {
+        (Some(disk_path), _) => {
+            ensure!(
+                overlays.overlays.len() == 1,
+                "the container has several disks; use --oci-layout to commit all of them"
+            );
+            ensure!(
+                !disk_path.try_exists()?,
+                "{} already exists",
+                disk_path.as_str()
+            );
+
+            let mut rollback = Rollback::default();
+            rollback.push({
+                let disk_path = disk_path.clone();
+                move || Ok(fs::remove_file(disk_path)?)
+            });
+
+            if args.rebase {
+                let overlay = &overlays.overlays[0];
+                rebase_overlay(overlay, disk_path, overlay.base_path.file_name().unwrap())?;
+            } else {
+                flatten_overlay(&overlays.overlays[0], disk_path)?;
+            }
+
+            rollback.commit();
+        }
+        (None, Some(layout_path)) => {
+            ensure!(
+                !layout_path.try_exists()?,
+                "{} already exists",
+                layout_path.as_str()
+            );
+
+            if args.rebase {
+                // the layout only gets a copy of each overlay's immediate base
+                for overlay in &overlays.overlays {
+                    ensure!(
+                        get_qcow2_backing_file(&overlay.base_path)?.is_none(),
+                        "VM image {} has a backing file itself; commit without --rebase instead",
+                        overlay.base_path.as_str()
+                    );
+                }
+            }
+
+            let mut rollback = Rollback::default();
+            fs::create_dir_all(layout_path)?;
+            rollback.push({
+                let layout_path = layout_path.clone();
+                move || remove_dir_all_if_exists(layout_path)
+            });
+
+            write_oci_layout(&overlays.overlays, args.rebase, layout_path)?;
+
+            rollback.commit();
+        }
+        (None, None) => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Writes the overlay and the VM image it is on top of into a standalone qcow2 file.
+fn flatten_overlay(overlay: &VmImageOverlay, to: &Path) -> Result<()> {
+    // The overlay's backing file is a path in the container, so point qemu-img at the VM image in
+    // the host instead.
+    let source = json!({
+        "driver": "qcow2",
+        "file": { "driver": "file", "filename": overlay.path },
+        "backing": {
+            "driver": overlay.base_format,
+            "file": { "driver": "file", "filename": overlay.base_path },
+        },
+    });
+
+    let status = Command::new("qemu-img")
+        .arg("convert")
+        .arg("-q")
+        .arg("-O")
+        .arg("qcow2")
+        .arg(format!("json:{source}"))
+        .arg(to)
+        .spawn()?
+        .wait()?;
+
+    ensure!(status.success(), "`qemu-img convert` failed");
+
+    Ok(())
+}
+
+/// Copies the overlay to a qcow2 file that is backed by the VM image file with the given name in the
+/// same directory.
+fn rebase_overlay(
+    overlay: &VmImageOverlay,
+    to: &Path,
+    backing_file_name: impl AsRef<Path>,
+) -> Result<()> {
+    // copy the file as is, as converting it would lose the distinction between clusters that the
+    // VM zeroed and ones it never wrote to
+    copy_file(&overlay.path, to)?;
+
+    let status = Command::new("qemu-img")
+        .arg("rebase")
+        .arg("-q")
+        .arg("-u")
+        .arg("-F")
+        .arg(&overlay.base_format)
+        .arg("-b")
+        .arg(backing_file_name.as_ref())
+        .arg(to)
+        .spawn()?
+        .wait()?;
+
+    ensure!(status.success(), "`qemu-img rebase` failed");
+
+    Ok(())
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    let status = Command::new("cp")
+        .arg("--sparse=always")
+        .arg("--reflink=auto")
+        .arg(from)
+        .arg(to)
+        .spawn()?
+        .wait()?;
+
+    ensure!(status.success(), "`cp` failed");
+
+    Ok(())
+}
+
+/// Writes an OCI image layout with a single image whose disks are `/disk/disk-<n>.qcow2`, the first
+/// one being the boot disk.
+///
+/// Without `rebase`, the image has a single layer with the flattened disks. With it, a first layer
+/// has the original VM image files as `/disk/base-<n>.<format>`, and a second one has the overlays
+/// rebased onto them. The first layer is the same for all images committed from containers using
+/// the same VM image, so registries and container storage only keep it once.
+fn write_oci_layout(overlays: &[VmImageOverlay], rebase: bool, layout_path: &Path) -> Result<()> {
+    let blobs_path = layout_path.join("blobs/sha256");
+    fs::create_dir_all(&blobs_path)?;
+
+    // write layers
+
+    let disk_file_name = |i: usize| format!("disk-{i}.qcow2");
+    let base_file_name =
+        |i: usize, overlay: &VmImageOverlay| format!("base-{i}.{}", overlay.base_format);
+
+    let layer_digests = if rebase {
+        vec![
+            write_layer(layout_path, &blobs_path, |disk_dir_path| {
+                for (i, overlay) in overlays.iter().enumerate() {
+                    copy_file(
+                        &overlay.base_path,
+                        &disk_dir_path.join(base_file_name(i, overlay)),
+                    )?;
+                }
+                Ok(())
+            })?,
+            write_layer(layout_path, &blobs_path, |disk_dir_path| {
+                for (i, overlay) in overlays.iter().enumerate() {
+                    rebase_overlay(
+                        overlay,
+                        &disk_dir_path.join(disk_file_name(i)),
+                        base_file_name(i, overlay),
+                    )?;
+                }
+                Ok(())
+            })?,
+        ]
+    } else {
+        vec![write_layer(layout_path, &blobs_path, |disk_dir_path| {
+            for (i, overlay) in overlays.iter().enumerate() {
+                flatten_overlay(overlay, &disk_dir_path.join(disk_file_name(i)))?;
+            }
+            Ok(())
+        })?]
+    };
+
+    // write config, manifest, and index
+
+    let write_blob = |value: serde_json::Value| -> Result<(String, usize)> {
+        let bytes = serde_json::to_vec(&value)?;
+        let path = layout_path.join("blob");
+        fs::write(&path, &bytes)?;
+        let digest = compute_file_digest(&path)?;
+        fs::rename(&path, blobs_path.join(&digest))?;
+        Ok((format!("sha256:{digest}"), bytes.len()))
+    };
+
+    let (config_digest, config_size) = write_blob(json!({
+        "architecture": oci_architecture(),
+        "os": "linux",
+        "config": {},
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": layer_digests
+                .iter()
+                .map(|(digest, _)| format!("sha256:{digest}"))
+                .collect::<Vec<_>>(),
+        },
+    }))?;
+
+    let (manifest_digest, manifest_size) = write_blob(json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": config_digest,
+            "size": config_size,
+        },
+        "layers": layer_digests
+            .iter()
+            .map(|(digest, size)| json!({
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "digest": format!("sha256:{digest}"),
+                "size": size,
+            }))
+            .collect::<Vec<_>>(),
+    }))?;
+
+    fs::write(
+        layout_path.join("index.json"),
+        serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": manifest_digest,
+                "size": manifest_size,
+            }],
+        }))?,
+    )?;
+
+    fs::write(
+        layout_path.join("oci-layout"),
+        serde_json::to_vec(&json!({ "imageLayoutVersion": "1.0.0" }))?,
+    )?;
+
+    Ok(())
+}
+
+/// Returns the host architecture as named in OCI image configs, which use Go's `GOARCH` names.
+fn oci_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        arch => arch,
+    }
+}
+
+/// Writes a layer blob containing the files that `fill` creates in the `/disk` directory it is
+/// given, and returns the layer's digest and size.
+fn write_layer(
+    layout_path: &Path,
+    blobs_path: &Path,
+    fill: impl FnOnce(&Path) -> Result<()>,
+) -> Result<(String, u64)> {
+    let staging_path = layout_path.join("staging");
+    fs::create_dir_all(staging_path.join("disk"))?;
+
+    fill(&staging_path.join("disk"))?;
+
+    let layer_path = layout_path.join("layer.tar");
+    let status = Command::new("tar")
+        .arg("--create")
+        .arg("--file")
+        .arg(&layer_path)
+        .arg("--directory")
+        .arg(&staging_path)
+        .arg("--sparse")
+        .arg("--sort=name")
+        .arg("--owner=0")
+        .arg("--group=0")
+        .arg("--numeric-owner")
+        .arg("--mtime=@0")
+        .arg("disk")
+        .spawn()?
+        .wait()?;
+
+    ensure!(status.success(), "`tar` failed");
+
+    fs::remove_dir_all(&staging_path)?;
+
+    let layer_digest = compute_file_digest(&layer_path)?;
+    let layer_size = layer_path.metadata()?.len();
+    fs::rename(&layer_path, blobs_path.join(&layer_digest))?;
+
+    Ok((layer_digest, layer_size))
+}
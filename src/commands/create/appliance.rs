@@ -0,0 +1,584 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::Deserialize;
+
+use crate::commands::create::custom_opts::DiskBus;
+use crate::util::{
+    convert_vm_image, get_file_digest, lock_cache_entry, remove_dir_all_if_exists, CacheEntryLock,
+    PathExt, VmImageInfo,
+};
+
+/// A virtual appliance, *i.e.*, an OVA archive or a Vagrant box, imported into the cache.
+pub struct Appliance {
+    /// Directory in the cache containing the appliance's disks converted to qcow2, along with its
+    /// OVF descriptor, if it has one.
+    pub dir_path: PathBuf,
+    /// Names of the disk files in `dir_path`, in the order they should be attached.
+    pub disk_file_names: Vec<String>,
+    pub hardware: ApplianceHardware,
+    /// Keeps the cache entry from being evicted.
+    pub lock: CacheEntryLock,
+}
+
+/// Virtual hardware declared by an appliance, which is used where the container doesn't say
+/// otherwise.
+#[derive(Debug, Default)]
+pub struct ApplianceHardware {
+    pub vcpus: Option<u64>,
+    pub memory: Option<u64>,
+    /// libvirt model of the network interface.
+    pub nic_model: Option<String>,
+    /// Bus through which the appliance's disks are attached.
+    pub disk_bus: Option<DiskBus>,
+    /// libvirt model of the SCSI controller, if `disk_bus` is SCSI.
+    pub scsi_model: Option<String>,
+}
+
+const OVF_FILE_NAME: &str = "appliance.ovf";
+
+/// Formats that appliance disks may be in. Anything else is refused rather than handed to
+/// `qemu-img`, which supports many more.
+const DISK_FORMATS: [&str; 4] = ["qcow2", "raw", "vdi", "vmdk"];
+
+/// Whether the given file is a tar archive, as OVA files and (decompressed) Vagrant boxes are.
+pub fn is_tar_archive(path: impl AsRef<Path>) -> Result<bool> {
+    const MAGIC_OFFSET: u64 = 257;
+    const MAGIC: &[u8; 5] = b"ustar";
+
+    let mut file = File::open(path)?;
+    let mut buf = vec![];
+
+    file.seek(SeekFrom::Start(MAGIC_OFFSET))?;
+    file.take(MAGIC.len() as u64).read_to_end(&mut buf)?;
+
+    Ok(buf == MAGIC)
+}
+
+/// Unpacks an appliance into the cache and converts its disks to qcow2, unless that was already
+/// done.
+pub fn import_appliance(archive_path: &Path, cache_dir: &Path) -> Result<Appliance> {
+    let digest = get_file_digest(archive_path, cache_dir)?;
+    let dir_path = cache_dir.join("appliances").join(digest);
+    let lock = lock_cache_entry(&dir_path)?;
+
+    if !dir_path.is_dir() {
+        let temp_dir_path = dir_path.with_extension(format!("tmp-{}", process::id()));
+
+        let result = unpack_appliance(archive_path, &temp_dir_path).and_then(|()| {
+            // another container may have concurrently imported the same appliance, in which case
+            // we just use that one
+            match fs::rename(&temp_dir_path, &dir_path) {
+                Ok(()) => Ok(()),
+                Err(_) if dir_path.is_dir() => remove_dir_all_if_exists(&temp_dir_path),
+                Err(e) => Err(e.into()),
+            }
+        });
+
+        if result.is_err() {
+            remove_dir_all_if_exists(&temp_dir_path)?;
+        }
+
+        result.with_context(|| format!("failed to import appliance {}", archive_path.as_str()))?;
+    }
+
+    let mut disk_file_names = vec![];
+    while dir_path
+        .join(disk_file_name(disk_file_names.len()))
+        .is_file()
+    {
+        disk_file_names.push(disk_file_name(disk_file_names.len()));
+    }
+
+    let ovf_path = dir_path.join(OVF_FILE_NAME);
+    let hardware = match ovf_path.is_file() {
+        true => Ovf::load(&ovf_path)?.hardware,
+        false => ApplianceHardware::default(),
+    };
+
+    Ok(Appliance {
+        dir_path,
+        disk_file_names,
+        hardware,
+        lock,
+    })
+}
+
+fn disk_file_name(index: usize) -> String {
+    format!("disk-{index}.qcow2")
+}
+
+fn unpack_appliance(archive_path: &Path, dir_path: &Path) -> Result<()> {
+    let archive_dir_path = dir_path.join("archive");
+    fs::create_dir_all(&archive_dir_path)?;
+
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg(archive_path)
+        .arg("-C")
+        .arg(&archive_dir_path)
+        .arg("--no-same-owner")
+        .arg("--no-same-permissions")
+        .status()?;
+
+    ensure!(status.success(), "`tar` failed");
+
+    let ovf_paths: Vec<PathBuf> = archive_dir_path
+        .read_dir()?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("ovf")))
+        .collect();
+
+    let metadata_path = archive_dir_path.join("metadata.json");
+
+    let disk_paths: Vec<PathBuf> = match ovf_paths.as_slice() {
+        [ovf_path] => {
+            // OVA archive, or Vagrant box for VirtualBox
+            let ovf_path = appliance_file_path(&archive_dir_path, ovf_path)?;
+            fs::copy(&ovf_path, dir_path.join(OVF_FILE_NAME))?;
+            Ovf::load(&ovf_path)?.disk_files
+        }
+        [] if metadata_path.symlink_metadata().is_ok() => {
+            // Vagrant box for libvirt
+            let metadata_path = appliance_file_path(&archive_dir_path, &metadata_path)?;
+            VagrantMetadata::load(&metadata_path)?.disk_files()
+        }
+        [] => bail!("not an OVA archive or Vagrant box"),
+        _ => bail!("more than one OVF descriptor found"),
+    };
+
+    ensure!(!disk_paths.is_empty(), "appliance has no disks");
+
+    for (i, disk_path) in disk_paths.iter().enumerate() {
+        ensure!(
+            disk_path
+                .components()
+                .all(|c| matches!(c, Component::Normal(_))),
+            "invalid disk path {} in appliance",
+            disk_path.as_str()
+        );
+
+        let disk_path = appliance_file_path(&archive_dir_path, &archive_dir_path.join(disk_path))?;
+        let format = get_disk_format(&disk_path)?;
+
+        convert_vm_image(&disk_path, &format, &dir_path.join(disk_file_name(i)))?;
+    }
+
+    remove_dir_all_if_exists(&archive_dir_path)
+}
+
+/// Checks that the given path, which comes from the appliance, is a regular file in the unpacked
+/// archive rather than, *e.g.*, a symlink to some file on the host.
+fn appliance_file_path(archive_dir_path: &Path, path: &Path) -> Result<PathBuf> {
+    let name = path
+        .strip_prefix(archive_dir_path)
+        .unwrap_or(path)
+        .display();
+
+    let metadata = path
+        .symlink_metadata()
+        .with_context(|| format!("file {name} not found in appliance"))?;
+    ensure!(
+        metadata.is_file(),
+        "{name} in appliance is not a regular file"
+    );
+
+    // guard against symlinks in parent directories too
+    let canonical_path = path.canonicalize()?;
+    ensure!(
+        canonical_path.starts_with(archive_dir_path.canonicalize()?),
+        "{name} in appliance is not a regular file"
+    );
+
+    Ok(canonical_path)
+}
+
+/// Probes the format of the given appliance disk, refusing formats outside of `DISK_FORMATS` and
+/// images that take data from other files, like VMDK descriptor files do.
+fn get_disk_format(path: &Path) -> Result<String> {
+    let info = VmImageInfo::of(path)?;
+    let name = path.file_name().unwrap().to_string_lossy();
+
+    ensure!(
+        DISK_FORMATS.contains(&info.format.as_str()),
+        "appliance disk {name} has unsupported format {}",
+        info.format
+    );
+    ensure!(
+        info.backing_file.is_none() && info.external_files.is_empty(),
+        "appliance disk {name} references other files, which is not supported"
+    );
+
+    Ok(info.format)
+}
+
+#[derive(Deserialize)]
+struct VagrantMetadata {
+    #[serde(default)]
+    disks: Vec<VagrantDisk>,
+}
+
+#[derive(Deserialize)]
+struct VagrantDisk {
+    path: PathBuf,
+}
+
+impl VagrantMetadata {
+    fn load(path: &Path) -> Result<VagrantMetadata> {
+        serde_json::from_reader(BufReader::new(File::open(path)?))
+            .with_context(|| format!("invalid Vagrant box metadata {}", path.as_str()))
+    }
+
+    fn disk_files(&self) -> Vec<PathBuf> {
+        // boxes that predate multi-disk support have a single disk with a fixed name
+        match self.disks.as_slice() {
+            [] => vec![PathBuf::from("box.img")],
+            disks => disks.iter().map(|d| d.path.clone()).collect(),
+        }
+    }
+}
+
+/// The parts of an OVF descriptor that we care about.
+struct Ovf {
+    /// Paths of the disk files relative to the descriptor, in the order they should be attached.
+    disk_files: Vec<PathBuf>,
+    hardware: ApplianceHardware,
+}
+
+impl Ovf {
+    fn load(path: &Path) -> Result<Ovf> {
+        let root = minidom::Element::from_reader_with_prefixes(
+            BufReader::new(File::open(path)?),
+            "".to_string(),
+        )
+        .with_context(|| format!("invalid OVF descriptor {}", path.as_str()))?;
+
+        Ovf::parse(&root).with_context(|| format!("invalid OVF descriptor {}", path.as_str()))
+    }
+
+    fn parse(root: &minidom::Element) -> Result<Ovf> {
+        // OVF versions and producers differ in namespaces, so we match elements and attributes by
+        // their local names only
+
+        let files: Vec<(&str, &str)> = children(root, "References")
+            .flat_map(|r| children(r, "File"))
+            .map(|f| Ok((attr(f, "id")?, attr(f, "href")?)))
+            .collect::<Result<_>>()?;
+
+        let disks: Vec<(&str, &str)> = children(root, "DiskSection")
+            .flat_map(|s| children(s, "Disk"))
+            .map(|d| Ok((attr(d, "diskId")?, attr(d, "fileRef")?)))
+            .collect::<Result<_>>()?;
+
+        let items: Vec<&minidom::Element> = children(root, "VirtualSystem")
+            .take(1)
+            .flat_map(|s| children(s, "VirtualHardwareSection"))
+            .flat_map(|s| children(s, "Item"))
+            .collect();
+
+        let mut hardware = ApplianceHardware::default();
+        let mut disk_ids = vec![];
+
+        for item in &items {
+            match property(item, "ResourceType").unwrap_or_default() {
+                // processor
+                "3" => {
+                    hardware.vcpus = property(item, "VirtualQuantity").and_then(|q| q.parse().ok())
+                }
+                // memory
+                "4" => {
+                    let quantity: Option<u64> =
+                        property(item, "VirtualQuantity").and_then(|q| q.parse().ok());
+                    let unit = allocation_unit(property(item, "AllocationUnits"))?;
+                    hardware.memory = quantity.and_then(|q| q.checked_mul(unit));
+                }
+                // ethernet adapter
+                "10" if hardware.nic_model.is_none() => {
+                    hardware.nic_model = property(item, "ResourceSubType").and_then(nic_model);
+                }
+                // disk drive
+                "17" => {
+                    let host_resource = property(item, "HostResource").unwrap_or_default();
+                    let disk_id = host_resource
+                        .trim_start_matches("ovf:")
+                        .trim_start_matches("/disk/");
+
+                    if disks.iter().any(|(id, _)| *id == disk_id) {
+                        disk_ids.push(disk_id);
+
+                        // all disks are attached to the bus of the controller of the first one
+                        if hardware.disk_bus.is_none() {
+                            let controller = property(item, "Parent").and_then(|parent| {
+                                items
+                                    .iter()
+                                    .find(|i| property(i, "InstanceID") == Some(parent))
+                            });
+
+                            if let Some(controller) = controller {
+                                (hardware.disk_bus, hardware.scsi_model) = controller_bus(
+                                    property(controller, "ResourceType").unwrap_or_default(),
+                                    property(controller, "ResourceSubType"),
+                                );
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // disks not attached to any drive come last, in the order they are declared
+        for (id, _) in &disks {
+            if !disk_ids.contains(id) {
+                disk_ids.push(id);
+            }
+        }
+
+        let disk_files = disk_ids
+            .iter()
+            .map(|disk_id| {
+                let (_, file_ref) = disks.iter().find(|(id, _)| id == disk_id).unwrap();
+                let (_, href) = files
+                    .iter()
+                    .find(|(id, _)| id == file_ref)
+                    .ok_or_else(|| anyhow!("disk {disk_id} references unknown file {file_ref}"))?;
+                Ok(PathBuf::from(href))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Ovf {
+            disk_files,
+            hardware,
+        })
+    }
+}
+
+fn children<'a>(
+    element: &'a minidom::Element,
+    name: &'a str,
+) -> impl Iterator<Item = &'a minidom::Element> {
+    element.children().filter(move |c| c.name() == name)
+}
+
+fn attr<'a>(element: &'a minidom::Element, name: &str) -> Result<&'a str> {
+    element
+        .attrs()
+        .find(|(n, _)| n.rsplit(':').next() == Some(name))
+        .map(|(_, value)| value)
+        .ok_or_else(|| anyhow!("{} element without {name} attribute", element.name()))
+}
+
+/// Returns the text of the given child of a virtual hardware `Item`, if it has that child.
+fn property<'a>(item: &'a minidom::Element, name: &str) -> Option<&'a str> {
+    let child = item.children().find(|c| c.name() == name)?;
+
+    // minidom only gives us owned text, so look for the single text node ourselves
+    match child.nodes().next()? {
+        minidom::Node::Text(text) => Some(text.trim()),
+        minidom::Node::Element(_) => None,
+    }
+}
+
+/// Returns the number of bytes in the given OVF allocation unit, *e.g.*, `byte * 2^20`.
+fn allocation_unit(unit: Option<&str>) -> Result<u64> {
+    let unit = match unit {
+        Some(unit) => unit.replace(' ', "").to_ascii_lowercase(),
+        None => return Ok(1 << 20), // megabytes, which is what producers use in practice
+    };
+
+    let shift = match unit.as_str() {
+        "byte" | "bytes" => 0,
+        "kilobytes" | "kb" => 10,
+        "megabytes" | "mb" => 20,
+        "gigabytes" | "gb" => 30,
+        _ => unit
+            .strip_prefix("byte*2^")
+            .and_then(|exp| exp.parse().ok())
+            .filter(|exp| *exp < 64)
+            .ok_or_else(|| anyhow!("unsupported memory allocation unit {unit}"))?,
+    };
+
+    Ok(1 << shift)
+}
+
+/// Returns the libvirt model for an OVF ethernet adapter subtype, if it is one QEMU emulates.
+fn nic_model(subtype: &str) -> Option<String> {
+    let model = match subtype.to_ascii_lowercase().as_str() {
+        "e1000" => "e1000",
+        "e1000e" => "e1000e",
+        "vmxnet3" => "vmxnet3",
+        "pcnet32" | "pcnet" => "pcnet",
+        "virtio" => "virtio",
+        _ => return None,
+    };
+
+    Some(model.to_string())
+}
+
+/// Returns the bus and SCSI controller model for an OVF disk controller.
+fn controller_bus(resource_type: &str, subtype: Option<&str>) -> (Option<DiskBus>, Option<String>) {
+    match resource_type {
+        // IDE, which q35 machines don't have, and other storage controllers (usually SATA)
+        "5" | "20" => (Some(DiskBus::Sata), None),
+        "6" => {
+            let model = match subtype.map(str::to_ascii_lowercase).as_deref() {
+                Some("lsilogic") => "lsilogic",
+                Some("lsilogicsas") => "lsisas1068",
+                Some("buslogic") => "buslogic",
+                Some("virtualscsi") => "vmpvscsi",
+                _ => "virtio-scsi",
+            };
+            (Some(DiskBus::Scsi), Some(model.to_string()))
+        }
+        _ => (None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::symlink;
+
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(None, 1 << 20; "default")]
+    #[test_case(Some("byte"), 1; "bytes")]
+    #[test_case(Some("KiloBytes"), 1 << 10; "kilobytes")]
+    #[test_case(Some("MB"), 1 << 20; "megabytes")]
+    #[test_case(Some("gigabytes"), 1 << 30; "gigabytes")]
+    #[test_case(Some("byte * 2^20"), 1 << 20; "power of two")]
+    fn allocation_unit_valid(unit: Option<&str>, expected: u64) {
+        assert_eq!(allocation_unit(unit).unwrap(), expected);
+    }
+
+    #[test_case("byte * 2^64"; "too large")]
+    #[test_case("byte * 10^6"; "power of ten")]
+    #[test_case("hertz"; "unknown")]
+    fn allocation_unit_invalid(unit: &str) {
+        assert!(allocation_unit(Some(unit)).is_err(), "{unit}");
+    }
+
+    fn parse(xml: &str) -> Result<Ovf> {
+        let root = minidom::Element::from_reader_with_prefixes(xml.as_bytes(), "".to_string())?;
+        Ovf::parse(&root)
+    }
+
+    const OVF: &str = r#"<?xml version="1.0"?>
+<Envelope xmlns="http://schemas.dmtf.org/ovf/envelope/1"
+    xmlns:ovf="http://schemas.dmtf.org/ovf/envelope/1"
+    xmlns:rasd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_ResourceAllocationSettingData">
+  <References>
+    <File ovf:id="file1" ovf:href="data.vmdk"/>
+    <File ovf:id="file2" ovf:href="root.vmdk"/>
+    <File ovf:id="file3" ovf:href="extra.vmdk"/>
+  </References>
+  <DiskSection>
+    <Disk ovf:diskId="data" ovf:fileRef="file1"/>
+    <Disk ovf:diskId="root" ovf:fileRef="file2"/>
+    <Disk ovf:diskId="extra" ovf:fileRef="file3"/>
+  </DiskSection>
+  <VirtualSystem ovf:id="vm">
+    <VirtualHardwareSection>
+      <Item>
+        <rasd:InstanceID>1</rasd:InstanceID>
+        <rasd:ResourceType>3</rasd:ResourceType>
+        <rasd:VirtualQuantity>2</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:AllocationUnits>byte * 2^20</rasd:AllocationUnits>
+        <rasd:InstanceID>2</rasd:InstanceID>
+        <rasd:ResourceType>4</rasd:ResourceType>
+        <rasd:VirtualQuantity>4096</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:InstanceID>3</rasd:InstanceID>
+        <rasd:ResourceSubType>lsilogic</rasd:ResourceSubType>
+        <rasd:ResourceType>6</rasd:ResourceType>
+      </Item>
+      <Item>
+        <rasd:InstanceID>4</rasd:InstanceID>
+        <rasd:ResourceSubType>E1000</rasd:ResourceSubType>
+        <rasd:ResourceType>10</rasd:ResourceType>
+      </Item>
+      <Item>
+        <rasd:HostResource>ovf:/disk/root</rasd:HostResource>
+        <rasd:InstanceID>5</rasd:InstanceID>
+        <rasd:Parent>3</rasd:Parent>
+        <rasd:ResourceType>17</rasd:ResourceType>
+      </Item>
+      <Item>
+        <rasd:HostResource>ovf:/disk/data</rasd:HostResource>
+        <rasd:InstanceID>6</rasd:InstanceID>
+        <rasd:Parent>3</rasd:Parent>
+        <rasd:ResourceType>17</rasd:ResourceType>
+      </Item>
+    </VirtualHardwareSection>
+  </VirtualSystem>
+</Envelope>"#;
+
+    #[test]
+    fn ovf_valid() {
+        let ovf = parse(OVF).unwrap();
+
+        assert_eq!(
+            ovf.disk_files,
+            [
+                PathBuf::from("root.vmdk"),
+                PathBuf::from("data.vmdk"),
+                PathBuf::from("extra.vmdk"),
+            ]
+        );
+        assert_eq!(ovf.hardware.vcpus, Some(2));
+        assert_eq!(ovf.hardware.memory, Some(4 << 30));
+        assert_eq!(ovf.hardware.nic_model.as_deref(), Some("e1000"));
+        assert_eq!(ovf.hardware.disk_bus, Some(DiskBus::Scsi));
+        assert_eq!(ovf.hardware.scsi_model.as_deref(), Some("lsilogic"));
+    }
+
+    #[test]
+    fn ovf_invalid_unknown_file() {
+        assert!(parse(&OVF.replace(r#"ovf:fileRef="file1""#, r#"ovf:fileRef="file9""#)).is_err());
+    }
+
+    #[test]
+    fn ovf_invalid_missing_href() {
+        assert!(parse(&OVF.replace(r#" ovf:href="data.vmdk""#, "")).is_err());
+    }
+
+    #[test]
+    fn ovf_invalid_memory_unit() {
+        assert!(parse(&OVF.replace("byte * 2^20", "byte * 10^6")).is_err());
+    }
+
+    #[test]
+    fn appliance_file_path_rejects_symlinks() {
+        let temp_dir_path = env::temp_dir().join(format!("crun-vm-test-{}", uuid::Uuid::new_v4()));
+        let archive_dir_path = temp_dir_path.join("archive");
+        fs::create_dir_all(archive_dir_path.join("dir")).unwrap();
+
+        fs::write(archive_dir_path.join("disk.vmdk"), "").unwrap();
+        fs::write(temp_dir_path.join("outside"), "").unwrap();
+        symlink("../outside", archive_dir_path.join("link.vmdk")).unwrap();
+        symlink("..", archive_dir_path.join("dir/parent")).unwrap();
+
+        let file_path =
+            |name: &str| appliance_file_path(&archive_dir_path, &archive_dir_path.join(name));
+
+        assert!(file_path("disk.vmdk").is_ok());
+        assert!(file_path("link.vmdk").is_err());
+        assert!(file_path("dir").is_err());
+        assert!(file_path("dir/parent/outside").is_err());
+        assert!(file_path("missing.vmdk").is_err());
+
+        fs::remove_dir_all(&temp_dir_path).unwrap();
+    }
+}
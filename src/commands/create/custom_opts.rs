@@ -1,47 +1,568 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+use std::fs::File;
+use std::io::BufReader;
 use std::iter;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use anyhow::{anyhow, ensure, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::{CommandFactory, Parser};
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde::Deserialize;
 
 use crate::commands::create::runtime_env::RuntimeEnv;
-use crate::util::PathExt;
+use crate::util::{parse_sha256_digest, ByteSize, PathExt};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DiskBus {
+    #[default]
+    Virtio,
+    Scsi,
+    Nvme,
+    Sata,
+}
+
+impl DiskBus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DiskBus::Virtio => "virtio",
+            DiskBus::Scsi => "scsi",
+            DiskBus::Nvme => "nvme",
+            DiskBus::Sata => "sata",
+        }
+    }
+}
+
+impl FromStr for DiskBus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DiskBus> {
+        match s {
+            "virtio" => Ok(DiskBus::Virtio),
+            "scsi" => Ok(DiskBus::Scsi),
+            "nvme" => Ok(DiskBus::Nvme),
+            "sata" => Ok(DiskBus::Sata),
+            _ => bail!("bus must be one of virtio, scsi, nvme, sata"),
+        }
+    }
+}
+
+/// The kind of device used to expose directories to the VM.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub enum FsDriver {
+    #[default]
+    Virtiofs,
+    NineP,
+}
+
+impl FsDriver {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FsDriver::Virtiofs => "virtiofs",
+            FsDriver::NineP => "9p",
+        }
+    }
+}
+
+impl FromStr for FsDriver {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<FsDriver> {
+        match s {
+            "virtiofs" => Ok(FsDriver::Virtiofs),
+            "9p" => Ok(FsDriver::NineP),
+            _ => bail!("filesystem driver must be one of virtiofs, 9p"),
+        }
+    }
+}
+
+impl TryFrom<String> for FsDriver {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<FsDriver> {
+        s.parse()
+    }
+}
+
+/// How a disk is exposed to the VM.
+#[derive(Clone, Debug, Default)]
+pub struct DiskOptions {
+    pub readonly: bool,
+    pub cache: Option<String>,
+    pub aio: Option<String>,
+    pub discard: Option<String>,
+    pub serial: Option<String>,
+    pub bus: DiskBus,
+    pub iothread: bool,
+    /// Path in the guest where the filesystem on the disk should be mounted.
+    pub mount: Option<PathBuf>,
+    pub fstype: Option<String>,
+    /// Whether to create a filesystem on the disk if it doesn't have one yet.
+    pub mkfs: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
 pub struct Blockdev {
     pub source: PathBuf,
     pub target: PathBuf,
     pub format: String,
+    pub options: DiskOptions,
 }
 
 impl FromStr for Blockdev {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Blockdev> {
-        lazy_static! {
-            static ref PATTERN: Regex =
-                Regex::new(r"^source=([^,]+),target=([^,]+),format=([^,]+)$").unwrap();
+        let mut source = None;
+        let mut target = None;
+        let mut format = None;
+        let mut options = DiskOptions::default();
+
+        for (key, value) in parse_key_values(s)? {
+            match key {
+                "source" => source = Some(PathBuf::from(require_value(key, value)?)),
+                "target" => target = Some(PathBuf::from(require_value(key, value)?)),
+                "format" => format = Some(require_value(key, value)?.to_string()),
+                "readonly" => options.readonly = parse_bool(key, value)?,
+                "cache" => {
+                    options.cache = Some(parse_one_of(
+                        key,
+                        value,
+                        &["none", "writethrough", "writeback", "directsync", "unsafe"],
+                    )?)
+                }
+                "aio" => {
+                    options.aio = Some(parse_one_of(
+                        key,
+                        value,
+                        &["io_uring", "native", "threads"],
+                    )?)
+                }
+                "discard" => {
+                    options.discard = Some(parse_one_of(key, value, &["unmap", "ignore"])?)
+                }
+                "serial" => options.serial = Some(require_value(key, value)?.to_string()),
+                "bus" => options.bus = require_value(key, value)?.parse()?,
+                "iothread" => options.iothread = parse_bool(key, value)?,
+                "mount" => options.mount = Some(PathBuf::from(require_value(key, value)?)),
+                "fstype" => options.fstype = Some(require_value(key, value)?.to_string()),
+                "mkfs" => options.mkfs = parse_one_of(key, value, &["auto", "never"])? == "auto",
+                _ => bail!("unknown key {key}"),
+            }
         }
 
-        let captures = PATTERN
-            .captures(s)
-            .ok_or_else(|| anyhow!("invalid --blockdev option"))?;
+        // the serial ends up in udev rules and /dev/disk/by-id/ paths, and virtio-blk serials are
+        // limited to 20 bytes
+        ensure!(
+            options.serial.as_ref().map_or(true, |serial| {
+                (1..=20).contains(&serial.len())
+                    && serial
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_.+-".contains(c))
+            }),
+            "serial must be 1 to 20 characters from A-Z, a-z, 0-9, _, ., +, and -"
+        );
+
+        ensure!(
+            options.aio.as_deref() != Some("native")
+                || matches!(options.cache.as_deref(), Some("none") | Some("directsync")),
+            "aio=native requires cache=none or cache=directsync"
+        );
+
+        ensure!(
+            !options.iothread || options.bus == DiskBus::Virtio,
+            "iothread requires bus=virtio"
+        );
+
+        ensure!(
+            options.mount.as_ref().map_or(true, |p| p.is_absolute()),
+            "mount must be an absolute path"
+        );
+
+        ensure!(
+            options.mount.is_some() || (options.fstype.is_none() && !options.mkfs),
+            "fstype and mkfs require mount"
+        );
+
+        ensure!(
+            !options.mkfs || options.fstype.is_some(),
+            "mkfs=auto requires fstype"
+        );
+
+        ensure!(
+            !options.mkfs || !options.readonly,
+            "mkfs=auto can't be used with readonly"
+        );
 
         let blockdev = Blockdev {
-            source: PathBuf::from(&captures[1]),
-            target: PathBuf::from(&captures[2]),
-            format: captures[3].to_string(),
+            source: source.ok_or_else(|| anyhow!("missing source"))?,
+            target: target.ok_or_else(|| anyhow!("missing target"))?,
+            format: format.ok_or_else(|| anyhow!("missing format"))?,
+            options,
         };
 
         Ok(blockdev)
     }
 }
 
+impl TryFrom<String> for Blockdev {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Blockdev> {
+        s.parse()
+    }
+}
+
+/// Overrides the format of a disk image file bind mounted into the container, which is otherwise
+/// detected automatically.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DiskFormat {
+    /// The destination path of the bind mount.
+    pub target: PathBuf,
+    pub format: String,
+}
+
+impl FromStr for DiskFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DiskFormat> {
+        let mut target = None;
+        let mut format = None;
+
+        for (key, value) in parse_key_values(s)? {
+            match key {
+                "target" => target = Some(PathBuf::from(require_value(key, value)?)),
+                "format" => format = Some(require_value(key, value)?.to_string()),
+                _ => bail!("unknown key {key}"),
+            }
+        }
+
+        Ok(DiskFormat {
+            target: target.ok_or_else(|| anyhow!("missing target"))?,
+            format: format.ok_or_else(|| anyhow!("missing format"))?,
+        })
+    }
+}
+
+impl TryFrom<String> for DiskFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<DiskFormat> {
+        s.parse()
+    }
+}
+
+/// An empty disk created for the container.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ScratchDisk {
+    pub size: u64,
+    pub format: String,
+    pub target: PathBuf,
+    /// File on the host in which to keep the disk across containers. It is created if it doesn't
+    /// exist yet.
+    pub persist: Option<PathBuf>,
+}
+
+impl FromStr for ScratchDisk {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ScratchDisk> {
+        let mut size = None;
+        let mut format = None;
+        let mut target = None;
+        let mut persist = None;
+
+        for (key, value) in parse_key_values(s)? {
+            match key {
+                "size" => {
+                    let ByteSize(bytes) = require_value(key, value)?.parse()?;
+                    size = Some(bytes);
+                }
+                "format" => format = Some(parse_one_of(key, value, &["raw", "qcow2"])?),
+                "target" => target = Some(PathBuf::from(require_value(key, value)?)),
+                "persist" => persist = Some(PathBuf::from(require_value(key, value)?)),
+                _ => bail!("unknown key {key}"),
+            }
+        }
+
+        let scratch_disk = ScratchDisk {
+            size: size.ok_or_else(|| anyhow!("missing size"))?,
+            format: format.unwrap_or_else(|| "qcow2".to_string()),
+            target: target.ok_or_else(|| anyhow!("missing target"))?,
+            persist,
+        };
+
+        ensure!(scratch_disk.size > 0, "size must be greater than zero");
+
+        Ok(scratch_disk)
+    }
+}
+
+impl TryFrom<String> for ScratchDisk {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<ScratchDisk> {
+        s.parse()
+    }
+}
+
+/// The blank disk that an installer ISO image installs to.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct InstallDisk {
+    pub size: u64,
+    pub format: String,
+    /// File on the host in which to keep the installed disk, so that it can be used as a VM image
+    /// afterwards. It is created if it doesn't exist yet.
+    pub persist: Option<PathBuf>,
+}
+
+impl Default for InstallDisk {
+    fn default() -> Self {
+        InstallDisk {
+            size: 20 << 30,
+            format: "qcow2".to_string(),
+            persist: None,
+        }
+    }
+}
+
+impl FromStr for InstallDisk {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<InstallDisk> {
+        let mut install_disk = InstallDisk::default();
+
+        for (key, value) in parse_key_values(s)? {
+            match key {
+                "size" => {
+                    let ByteSize(bytes) = require_value(key, value)?.parse()?;
+                    install_disk.size = bytes;
+                }
+                "format" => install_disk.format = parse_one_of(key, value, &["raw", "qcow2"])?,
+                "persist" => install_disk.persist = Some(PathBuf::from(require_value(key, value)?)),
+                _ => bail!("unknown key {key}"),
+            }
+        }
+
+        ensure!(install_disk.size > 0, "size must be greater than zero");
+
+        Ok(install_disk)
+    }
+}
+
+impl TryFrom<String> for InstallDisk {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<InstallDisk> {
+        s.parse()
+    }
+}
+
+/// The expected digest of a VM image file, given as `[<file>=]sha256:<hex>`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ImageDigest {
+    /// Path of the VM image file in the container image, or relative to the directory containing
+    /// the VM image files. Refers to the boot disk if not given.
+    pub file: Option<PathBuf>,
+    /// Hex-encoded SHA-256 digest.
+    pub sha256: String,
+}
+
+impl FromStr for ImageDigest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ImageDigest> {
+        let (file, digest) = match s.rsplit_once('=') {
+            Some((file, digest)) => (Some(PathBuf::from(file)), digest),
+            None => (None, s),
+        };
+
+        let hex = digest
+            .strip_prefix("sha256:")
+            .ok_or_else(|| anyhow!("digest must be of the form sha256:<hex>"))?;
+
+        Ok(ImageDigest {
+            file,
+            sha256: parse_sha256_digest(hex)?,
+        })
+    }
+}
+
+impl TryFrom<String> for ImageDigest {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<ImageDigest> {
+        s.parse()
+    }
+}
+
+/// A uid or gid mapping between the guest and the container, given as `<guest>:<container>:<count>`.
+#[derive(Clone, Copy, Debug)]
+pub struct IdMap {
+    pub guest: u32,
+    pub container: u32,
+    pub count: u32,
+}
+
+impl FromStr for IdMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<IdMap> {
+        let parts = s
+            .split(':')
+            .map(str::parse)
+            .collect::<Result<Vec<u32>, _>>()
+            .ok()
+            .filter(|parts| parts.len() == 3)
+            .ok_or_else(|| anyhow!("id mapping must be of the form <guest>:<container>:<count>"))?;
+
+        Ok(IdMap {
+            guest: parts[0],
+            container: parts[1],
+            count: parts[2],
+        })
+    }
+}
+
+/// How a directory is exposed to the VM through virtiofs.
+#[derive(Clone, Debug, Default)]
+pub struct VirtiofsOptions {
+    pub cache: Option<String>,
+    pub thread_pool_size: Option<u32>,
+    pub uid_map: Option<IdMap>,
+    pub gid_map: Option<IdMap>,
+}
+
+impl VirtiofsOptions {
+    /// Extra arguments to pass to virtiofsd for the options that libvirt can't express.
+    pub fn virtiofsd_args(&self) -> Vec<String> {
+        let mut args = vec![];
+
+        if let Some(cache) = &self.cache {
+            args.push(format!("--cache={cache}"));
+        }
+
+        if let Some(map) = self.uid_map {
+            args.push(format!(
+                "--translate-uid=map:{}:{}:{}",
+                map.guest, map.container, map.count
+            ));
+        }
+
+        if let Some(map) = self.gid_map {
+            args.push(format!(
+                "--translate-gid=map:{}:{}:{}",
+                map.guest, map.container, map.count
+            ));
+        }
+
+        args
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct VirtiofsOpt {
+    /// The guest path of the directory mount that the options apply to.
+    pub target: PathBuf,
+    pub options: VirtiofsOptions,
+}
+
+impl FromStr for VirtiofsOpt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<VirtiofsOpt> {
+        let mut target = None;
+        let mut options = VirtiofsOptions::default();
+
+        for (key, value) in parse_key_values(s)? {
+            match key {
+                "target" => target = Some(PathBuf::from(require_value(key, value)?)),
+                "cache" => {
+                    options.cache = Some(parse_one_of(
+                        key,
+                        value,
+                        &["auto", "always", "never", "metadata"],
+                    )?)
+                }
+                "thread-pool-size" => {
+                    options.thread_pool_size = Some(
+                        require_value(key, value)?
+                            .parse()
+                            .map_err(|_| anyhow!("{key} must be a non-negative integer"))?,
+                    )
+                }
+                "uid-map" => options.uid_map = Some(require_value(key, value)?.parse()?),
+                "gid-map" => options.gid_map = Some(require_value(key, value)?.parse()?),
+                _ => bail!("unknown key {key}"),
+            }
+        }
+
+        let target = target.ok_or_else(|| anyhow!("missing target"))?;
+        ensure!(target.is_absolute(), "target must be an absolute path");
+
+        Ok(VirtiofsOpt { target, options })
+    }
+}
+
+impl TryFrom<String> for VirtiofsOpt {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<VirtiofsOpt> {
+        s.parse()
+    }
+}
+
+/// Splits a comma-separated list of `key=value` pairs, where `=value` may be omitted, failing if a
+/// key is given more than once.
+fn parse_key_values(s: &str) -> Result<Vec<(&str, Option<&str>)>> {
+    let mut pairs: Vec<(&str, Option<&str>)> = vec![];
+
+    for pair in s.split(',') {
+        let (key, value) = match pair.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (pair, None),
+        };
+
+        ensure!(
+            pairs.iter().all(|(k, _)| *k != key),
+            "{key} given more than once"
+        );
+
+        pairs.push((key, value));
+    }
+
+    Ok(pairs)
+}
+
+fn require_value<'a>(key: &str, value: Option<&'a str>) -> Result<&'a str> {
+    value.ok_or_else(|| anyhow!("{key} requires a value"))
+}
+
+/// Boolean keys may also be given without a value, meaning `true`.
+fn parse_bool(key: &str, value: Option<&str>) -> Result<bool> {
+    match value {
+        None | Some("true") | Some("on") => Ok(true),
+        Some("false") | Some("off") => Ok(false),
+        Some(_) => bail!("{key} must be true, false, on, or off"),
+    }
+}
+
+fn parse_one_of(key: &str, value: Option<&str>, allowed: &[&str]) -> Result<String> {
+    match value {
+        Some(v) if allowed.contains(&v) => Ok(v.to_string()),
+        _ => bail!("{key} must be one of {}", allowed.join(", ")),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct VfioPciAddress {
     pub domain: u16,
@@ -122,6 +643,20 @@ pub struct CustomOptions {
     pub password: Option<String>,
     pub merge_libvirt_xml: Vec<PathBuf>,
     pub print_libvirt_xml: bool,
+    pub memory: Option<u64>,
+    pub virtiofs_opt: Vec<VirtiofsOpt>,
+    pub fs_driver: Option<FsDriver>,
+    pub scratch_disk: Vec<ScratchDisk>,
+    pub disk_size: Option<u64>,
+    pub confirm_resize: bool,
+    pub disk_format: Vec<DiskFormat>,
+    pub boot_disk: Option<PathBuf>,
+    pub install_disk: Option<InstallDisk>,
+    pub image_digest: Vec<ImageDigest>,
+    pub image_key: Option<PathBuf>,
+    pub encrypt_overlay: bool,
+    pub overlay_dir: Option<PathBuf>,
+    pub uefi: bool,
 }
 
 impl TryFrom<CustomOptionsRaw> for CustomOptions {
@@ -130,7 +665,7 @@ impl TryFrom<CustomOptionsRaw> for CustomOptions {
     fn try_from(opts: CustomOptionsRaw) -> Result<Self> {
         Ok(Self {
             blockdev: opts.blockdev,
-            persistent: opts.persistent,
+            persistent: opts.persistent.unwrap_or(false),
             cloud_init: opts.cloud_init,
             ignition: opts.ignition,
             vfio_pci: opts
@@ -145,18 +680,37 @@ impl TryFrom<CustomOptionsRaw> for CustomOptions {
                 .collect::<Result<_>>()?,
             password: opts.password,
             merge_libvirt_xml: opts.merge_libvirt_xml,
-            print_libvirt_xml: opts.print_libvirt_xml,
+            print_libvirt_xml: opts.print_libvirt_xml.unwrap_or(false),
+            memory: opts.memory.map(|ByteSize(size)| size),
+            virtiofs_opt: opts.virtiofs_opt,
+            fs_driver: opts.fs_driver,
+            scratch_disk: opts.scratch_disk,
+            disk_size: opts.disk_size.map(|ByteSize(size)| size),
+            confirm_resize: opts.confirm_resize.unwrap_or(false),
+            disk_format: opts.disk_format,
+            boot_disk: opts.boot_disk,
+            install_disk: opts.install_disk,
+            image_digest: opts.image_digest,
+            image_key: opts.image_key,
+            encrypt_overlay: opts.encrypt_overlay.unwrap_or(false),
+            overlay_dir: opts.overlay_dir,
+            uefi: opts.uefi.unwrap_or(false),
         })
     }
 }
 
-#[derive(clap::Parser, Debug)]
-struct CustomOptionsRaw {
+/// Custom options as given in container arguments, annotations, or config files.
+///
+/// Flags are optional so that they can override defaults set in config files, *e.g.*,
+/// `--persistent=false`.
+#[derive(clap::Parser, Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CustomOptionsRaw {
     #[clap(long)]
     blockdev: Vec<Blockdev>,
 
-    #[clap(long)]
-    persistent: bool,
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    persistent: Option<bool>,
 
     #[clap(long)]
     cloud_init: Option<PathBuf>,
@@ -176,27 +730,377 @@ struct CustomOptionsRaw {
     #[clap(long)]
     merge_libvirt_xml: Vec<PathBuf>,
 
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    print_libvirt_xml: Option<bool>,
+
+    /// VM memory size to use when the container has no memory limit. Can only be set in config
+    /// files.
+    #[clap(skip)]
+    memory: Option<ByteSize>,
+
+    #[clap(long)]
+    virtiofs_opt: Vec<VirtiofsOpt>,
+
+    #[clap(long)]
+    fs_driver: Option<FsDriver>,
+
+    #[clap(long)]
+    scratch_disk: Vec<ScratchDisk>,
+
     #[clap(long)]
-    print_libvirt_xml: bool,
+    disk_size: Option<ByteSize>,
+
+    /// Allow `--disk-size` to grow the VM image in place when using `--persistent`.
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    confirm_resize: Option<bool>,
+
+    #[clap(long)]
+    disk_format: Vec<DiskFormat>,
+
+    /// Which of the VM image files to boot from, as a path in the container image or relative to
+    /// the directory containing the VM image files.
+    #[clap(long)]
+    boot_disk: Option<PathBuf>,
+
+    #[clap(long)]
+    install_disk: Option<InstallDisk>,
+
+    #[clap(long)]
+    image_digest: Vec<ImageDigest>,
+
+    /// File in the container with the passphrase of LUKS-encrypted VM images, *e.g.*, a Podman
+    /// secret under `/run/secrets/`.
+    #[clap(long)]
+    image_key: Option<PathBuf>,
+
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    encrypt_overlay: Option<bool>,
+
+    /// Directory on the host, *e.g.*, the mount point of a Podman volume, in which to keep the
+    /// qcow2 overlays holding the VM's changes to its image, so that they outlive the container.
+    #[clap(long)]
+    overlay_dir: Option<PathBuf>,
+
+    /// Boot the VM with UEFI firmware instead of BIOS.
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    uefi: Option<bool>,
+}
+
+/// Options that VM image config files may set.
+const IMAGE_CONFIG_FILE_OPTIONS: &[&str] = &[
+    "cloud-init",
+    "ignition",
+    "password",
+    "merge-libvirt-xml",
+    "memory",
+    "disk-size",
+    "boot-disk",
+    "image-digest",
+    "uefi",
+];
+
+impl CustomOptionsRaw {
+    /// Parses the options given as `io.crun-vm.<option>` annotations.
+    ///
+    /// Flags take values `true` or `false`, and options that may be given several times take one
+    /// value per line.
+    fn from_annotations(spec: &oci_spec::runtime::Spec) -> Result<Self> {
+        const PREFIX: &str = "io.crun-vm.";
+
+        let mut annotations: Vec<_> = spec
+            .annotations()
+            .iter()
+            .flatten()
+            .filter_map(|(key, value)| Some((key.as_str(), key.strip_prefix(PREFIX)?, value)))
+            .collect();
+
+        annotations.sort(); // so that errors are deterministic
+
+        let command = CustomOptionsRaw::command();
+        let mut args = vec![];
+
+        for (key, option, value) in annotations {
+            ensure!(
+                command
+                    .get_arguments()
+                    .any(|a| a.get_long() == Some(option)),
+                "unknown annotation {key}"
+            );
+
+            for line in value.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                args.push(format!("--{option}={line}"));
+            }
+        }
+
+        CustomOptionsRaw::try_parse_from(iter::once("annotations".to_string()).chain(args))
+            .context("invalid io.crun-vm.* annotations")
+    }
+
+    /// Loads a VM image config file, which provides defaults for some options.
+    ///
+    /// Relative paths in the file are interpreted relative to the directory containing it, and
+    /// absolute paths relative to `root_path`. No path may point outside of `root_path`.
+    pub fn from_image_config_file(path: &Path, root_path: &Path) -> Result<Self> {
+        let invalid = || format!("invalid VM image config file {}", path.as_str());
+
+        let value: serde_yaml::Value =
+            serde_yaml::from_reader(File::open(path).map(BufReader::new)?).with_context(invalid)?;
+
+        if let Some(mapping) = value.as_mapping() {
+            for key in mapping.keys() {
+                ensure!(
+                    key.as_str()
+                        .is_some_and(|k| IMAGE_CONFIG_FILE_OPTIONS.contains(&k)),
+                    "VM image config files may only set options {}",
+                    IMAGE_CONFIG_FILE_OPTIONS.join(", ")
+                );
+            }
+        }
+
+        let mut options: CustomOptionsRaw = if value.is_null() {
+            CustomOptionsRaw::default()
+        } else {
+            serde_yaml::from_value(value).with_context(invalid)?
+        };
+
+        let config_dir_path = path.parent().unwrap();
+        let root_path = root_path.canonicalize()?;
+
+        let resolve = |p: &mut PathBuf| -> Result<()> {
+            let resolved = match p.strip_prefix("/") {
+                Ok(relative) => root_path.join(relative),
+                Err(_) => config_dir_path.join(&p),
+            };
+
+            let resolved = resolved
+                .canonicalize()
+                .with_context(|| format!("can't find {}", p.as_str()))?;
+
+            ensure!(
+                resolved.starts_with(&root_path),
+                "path {} in VM image config file points outside of the image",
+                p.as_str()
+            );
+
+            *p = resolved;
+            Ok(())
+        };
+
+        options.cloud_init.iter_mut().try_for_each(resolve)?;
+        options.ignition.iter_mut().try_for_each(resolve)?;
+        options.merge_libvirt_xml.iter_mut().try_for_each(resolve)?;
+
+        Ok(options)
+    }
+
+    /// Merges options given as container arguments with options given as annotations.
+    ///
+    /// Options that may be given several times are concatenated, and it is an error for options
+    /// that may be given only once to be given different values in each.
+    fn merge(self, other: CustomOptionsRaw) -> Result<Self> {
+        fn merge_single<T: PartialEq>(name: &str, a: Option<T>, b: Option<T>) -> Result<Option<T>> {
+            match (a, b) {
+                (Some(a), Some(b)) => {
+                    ensure!(
+                        a == b,
+                        "option --{name} is given conflicting values in arguments and annotations"
+                    );
+                    Ok(Some(a))
+                }
+                (a, b) => Ok(a.or(b)),
+            }
+        }
+
+        Ok(Self {
+            blockdev: concat(self.blockdev, other.blockdev),
+            persistent: merge_single("persistent", self.persistent, other.persistent)?,
+            cloud_init: merge_single("cloud-init", self.cloud_init, other.cloud_init)?,
+            ignition: merge_single("ignition", self.ignition, other.ignition)?,
+            vfio_pci: concat(self.vfio_pci, other.vfio_pci),
+            vfio_pci_mdev: concat(self.vfio_pci_mdev, other.vfio_pci_mdev),
+            password: merge_single("password", self.password, other.password)?,
+            merge_libvirt_xml: concat(self.merge_libvirt_xml, other.merge_libvirt_xml),
+            print_libvirt_xml: merge_single(
+                "print-libvirt-xml",
+                self.print_libvirt_xml,
+                other.print_libvirt_xml,
+            )?,
+            memory: merge_single("memory", self.memory, other.memory)?,
+            virtiofs_opt: concat(self.virtiofs_opt, other.virtiofs_opt),
+            fs_driver: merge_single("fs-driver", self.fs_driver, other.fs_driver)?,
+            scratch_disk: concat(self.scratch_disk, other.scratch_disk),
+            disk_size: merge_single("disk-size", self.disk_size, other.disk_size)?,
+            confirm_resize: merge_single(
+                "confirm-resize",
+                self.confirm_resize,
+                other.confirm_resize,
+            )?,
+            disk_format: concat(self.disk_format, other.disk_format),
+            boot_disk: merge_single("boot-disk", self.boot_disk, other.boot_disk)?,
+            install_disk: merge_single("install-disk", self.install_disk, other.install_disk)?,
+            image_digest: concat(self.image_digest, other.image_digest),
+            image_key: merge_single("image-key", self.image_key, other.image_key)?,
+            encrypt_overlay: merge_single(
+                "encrypt-overlay",
+                self.encrypt_overlay,
+                other.encrypt_overlay,
+            )?,
+            overlay_dir: merge_single("overlay-dir", self.overlay_dir, other.overlay_dir)?,
+            uefi: merge_single("uefi", self.uefi, other.uefi)?,
+        })
+    }
+
+    /// Returns all paths given in options.
+    pub fn paths(&self) -> Vec<&Path> {
+        self.paths_by_option().into_iter().map(|(_, p)| p).collect()
+    }
+
+    /// Returns all paths given in options, each along with the option that specifies it.
+    fn paths_by_option(&self) -> Vec<(&'static str, &Path)> {
+        let mut paths: Vec<(&'static str, &Path)> = vec![];
+
+        for blockdev in &self.blockdev {
+            paths.push(("--blockdev", &blockdev.source));
+            paths.push(("--blockdev", &blockdev.target));
+        }
+
+        for disk_format in &self.disk_format {
+            paths.push(("--disk-format target=", &disk_format.target));
+        }
+
+        for scratch_disk in &self.scratch_disk {
+            paths.push(("--scratch-disk target=", &scratch_disk.target));
+            if let Some(persist) = &scratch_disk.persist {
+                paths.push(("--scratch-disk persist=", persist));
+            }
+        }
+
+        if let Some(persist) = self.install_disk.as_ref().and_then(|d| d.persist.as_ref()) {
+            paths.push(("--install-disk persist=", persist));
+        }
+
+        let single = [
+            ("--overlay-dir", &self.overlay_dir),
+            ("--cloud-init", &self.cloud_init),
+            ("--ignition", &self.ignition),
+        ];
+
+        for (option, path) in single {
+            if let Some(path) = path {
+                paths.push((option, path));
+            }
+        }
+
+        let multiple = [
+            ("--vfio-pci", &self.vfio_pci),
+            ("--vfio-pci-mdev", &self.vfio_pci_mdev),
+            ("--merge-libvirt-xml", &self.merge_libvirt_xml),
+        ];
+
+        for (option, list) in multiple {
+            paths.extend(list.iter().map(|p| (option, p.as_path())));
+        }
+
+        paths
+    }
+
+    /// Returns the options that specify relative paths, without duplicates.
+    fn options_with_relative_paths(&self) -> Vec<&'static str> {
+        let mut options: Vec<&'static str> = vec![];
+
+        for (option, path) in self.paths_by_option() {
+            if !path.is_absolute() && !options.contains(&option) {
+                options.push(option);
+            }
+        }
+
+        options
+    }
+
+    /// Fills in options that weren't given with the values in `defaults`.
+    ///
+    /// Options that may be given several times are concatenated, with `defaults` coming first.
+    pub fn with_defaults(self, defaults: CustomOptionsRaw) -> Self {
+        Self {
+            blockdev: concat(defaults.blockdev, self.blockdev),
+            persistent: self.persistent.or(defaults.persistent),
+            cloud_init: self.cloud_init.or(defaults.cloud_init),
+            ignition: self.ignition.or(defaults.ignition),
+            vfio_pci: concat(defaults.vfio_pci, self.vfio_pci),
+            vfio_pci_mdev: concat(defaults.vfio_pci_mdev, self.vfio_pci_mdev),
+            password: self.password.or(defaults.password),
+            merge_libvirt_xml: concat(defaults.merge_libvirt_xml, self.merge_libvirt_xml),
+            print_libvirt_xml: self.print_libvirt_xml.or(defaults.print_libvirt_xml),
+            memory: self.memory.or(defaults.memory),
+            virtiofs_opt: concat(defaults.virtiofs_opt, self.virtiofs_opt),
+            fs_driver: self.fs_driver.or(defaults.fs_driver),
+            scratch_disk: concat(defaults.scratch_disk, self.scratch_disk),
+            disk_size: self.disk_size.or(defaults.disk_size),
+            confirm_resize: self.confirm_resize.or(defaults.confirm_resize),
+            disk_format: concat(defaults.disk_format, self.disk_format),
+            boot_disk: self.boot_disk.or(defaults.boot_disk),
+            install_disk: self.install_disk.or(defaults.install_disk),
+            image_digest: concat(defaults.image_digest, self.image_digest),
+            image_key: self.image_key.or(defaults.image_key),
+            encrypt_overlay: self.encrypt_overlay.or(defaults.encrypt_overlay),
+            overlay_dir: self.overlay_dir.or(defaults.overlay_dir),
+            uefi: self.uefi.or(defaults.uefi),
+        }
+    }
+}
+
+fn concat<T>(mut a: Vec<T>, b: Vec<T>) -> Vec<T> {
+    a.extend(b);
+    a
+}
+
+/// Returns the given container arguments without the entrypoint, if any.
+///
+/// The entrypoint precedes the arguments given after the image. If the container engine recorded
+/// the `entrypoint` and the arguments start with it, it is stripped, along with a `--` argument
+/// that directly follows it, if any. A `--` anywhere else is left alone, as it may be an option
+/// value.
+fn strip_entrypoint<'a, T: AsRef<str>>(args: &'a [T], entrypoint: Option<&[String]>) -> &'a [T] {
+    let args = match entrypoint {
+        Some(entrypoint)
+            if args.len() >= entrypoint.len()
+                && args.iter().zip(entrypoint).all(|(a, e)| a.as_ref() == e) =>
+        {
+            &args[entrypoint.len()..]
+        }
+        _ => args,
+    };
+
+    match args.first() {
+        Some(arg) if arg.as_ref() == "--" => &args[1..],
+        _ => args,
+    }
 }
 
 impl CustomOptions {
-    pub fn from_spec(spec: &oci_spec::runtime::Spec, env: RuntimeEnv) -> Result<Self> {
-        let args = spec
+    /// Gathers the options given as container arguments and annotations, falling back to
+    /// `defaults` for any that weren't given. The container's `entrypoint`, if known, is ignored.
+    pub fn from_spec(
+        spec: &oci_spec::runtime::Spec,
+        env: RuntimeEnv,
+        entrypoint: Option<&[String]>,
+        defaults: CustomOptionsRaw,
+    ) -> Result<Self> {
+        let args: Vec<&String> = spec
             .process()
             .as_ref()
             .unwrap()
             .args()
             .iter()
             .flatten()
-            .filter(|arg| !arg.trim().is_empty());
+            .filter(|arg| !arg.trim().is_empty())
+            .collect();
 
-        // TODO: We currently assume that no entrypoint is given (either by being set by in the
-        // container image or through --entrypoint). Must somehow find whether the first arg is the
-        // entrypoint and ignore it in that case.
         let mut options = CustomOptionsRaw::parse_from(
-            iter::once(&"podman run [<podman-opts>] <image>".to_string()).chain(args),
-        );
+            iter::once(&"podman run [<podman-opts>] <image>".to_string())
+                .chain(strip_entrypoint(&args, entrypoint).iter().copied()),
+        )
+        .merge(CustomOptionsRaw::from_annotations(spec)?)?;
 
         fn all_are_absolute(iter: impl IntoIterator<Item = impl AsRef<Path>>) -> bool {
             iter.into_iter().all(|p| p.as_ref().is_absolute())
@@ -229,18 +1133,11 @@ impl CustomOptions {
                 // that launched `docker-run`, so we require custom option paths to be absolute.
                 //
                 // TODO: There must be a better way...
+                let relative = options.options_with_relative_paths();
                 ensure!(
-                    all_are_absolute(options.blockdev.iter().flat_map(|b| [&b.source, &b.target]))
-                        && all_are_absolute(&options.cloud_init)
-                        && all_are_absolute(&options.ignition)
-                        && all_are_absolute(&options.vfio_pci)
-                        && all_are_absolute(&options.vfio_pci_mdev)
-                        && all_are_absolute(&options.merge_libvirt_xml),
-                    concat!(
-                        "paths specified using --blockdev, --cloud-init, --ignition, --vfio-pci,",
-                        " --vfio-pci-mdev, or --merge-libvirt-xml must be absolute when using",
-                        " crun-vm as a Docker runtime",
-                    ),
+                    relative.is_empty(),
+                    "paths specified using {} must be absolute when using crun-vm as a Docker runtime",
+                    relative.join(", "),
                 );
             }
             RuntimeEnv::Kubernetes => {
@@ -250,14 +1147,28 @@ impl CustomOptions {
                     all_are_absolute(options.blockdev.iter().flat_map(|b| [&b.source, &b.target]))
                         && all_are_absolute(&options.cloud_init)
                         && all_are_absolute(&options.ignition)
-                        && all_are_absolute(&options.merge_libvirt_xml),
+                        && all_are_absolute(&options.merge_libvirt_xml)
+                        && all_are_absolute(options.disk_format.iter().map(|f| &f.target))
+                        && all_are_absolute(options.scratch_disk.iter().map(|d| &d.target)),
                     concat!(
-                        "paths specified using --blockdev, --cloud-init, --ignition, or",
-                        " --merge-libvirt-xml must be absolute when using crun-vm as a",
-                        " Kubernetes runtime",
+                        "paths specified using --blockdev, --disk-format target=, --scratch-disk",
+                        " target=, --cloud-init, --ignition, or --merge-libvirt-xml must be",
+                        " absolute when using crun-vm as a Kubernetes runtime",
                     ),
                 );
 
+                // These options name host paths that crun-vm writes to, which needn't exist yet and
+                // so can't be mapped from paths in the container.
+                ensure!(
+                    options.overlay_dir.is_none()
+                        && options.scratch_disk.iter().all(|d| d.persist.is_none())
+                        && options.install_disk.iter().all(|d| d.persist.is_none()),
+                    concat!(
+                        "options --overlay-dir, --scratch-disk persist=, and --install-disk",
+                        " persist= are not allowed when using crun-vm as a Kubernetes runtime",
+                    )
+                );
+
                 ensure!(
                     options.vfio_pci.is_empty() && options.vfio_pci_mdev.is_empty(),
                     concat!(
@@ -286,6 +1197,187 @@ impl CustomOptions {
             RuntimeEnv::Other => {}
         }
 
-        options.try_into()
+        options.with_defaults(defaults).try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test]
+    fn blockdev_accepts_keys_in_any_order() {
+        let blockdev: Blockdev =
+            "format=qcow2,target=/data,source=/disk.qcow2,readonly,cache=none,\
+            aio=native,discard=unmap,serial=db-disk_1.0+x,bus=virtio,iothread"
+                .parse()
+                .unwrap();
+
+        assert_eq!(blockdev.source, Path::new("/disk.qcow2"));
+        assert_eq!(blockdev.target, Path::new("/data"));
+        assert_eq!(blockdev.format, "qcow2");
+        assert!(blockdev.options.readonly);
+        assert_eq!(blockdev.options.cache.as_deref(), Some("none"));
+        assert_eq!(blockdev.options.aio.as_deref(), Some("native"));
+        assert_eq!(blockdev.options.discard.as_deref(), Some("unmap"));
+        assert_eq!(blockdev.options.serial.as_deref(), Some("db-disk_1.0+x"));
+        assert_eq!(blockdev.options.bus, DiskBus::Virtio);
+        assert!(blockdev.options.iothread);
+    }
+
+    #[test_case("serial=12345678901234567890"; "20 characters")]
+    #[test_case("serial=ABC-def_0.1+2"; "all allowed characters")]
+    #[test_case("mount=/mnt,fstype=ext4,mkfs=auto"; "mkfs")]
+    #[test_case("bus=nvme,readonly=off"; "bus")]
+    fn blockdev_valid(options: &str) {
+        let s = format!("source=/a,target=/b,format=raw,{options}");
+        assert!(s.parse::<Blockdev>().is_ok(), "{s}");
+    }
+
+    #[test_case("source=/a,target=/b"; "missing format")]
+    #[test_case("source=/a,source=/b,target=/b,format=raw"; "repeated key")]
+    #[test_case("source=/a,target=/b,format=raw,foo=bar"; "unknown key")]
+    #[test_case("source=/a,target=/b,format=raw,serial=123456789012345678901"; "serial too long")]
+    #[test_case("source=/a,target=/b,format=raw,serial=a b"; "serial with space")]
+    #[test_case("source=/a,target=/b,format=raw,serial=a\"b"; "serial with quote")]
+    #[test_case("source=/a,target=/b,format=raw,serial=a/b"; "serial with slash")]
+    #[test_case("source=/a,target=/b,format=raw,serial="; "empty serial")]
+    #[test_case("source=/a,target=/b,format=raw,serial"; "serial without value")]
+    #[test_case("source=/a,target=/b,format=raw,cache=bogus"; "invalid cache")]
+    #[test_case("source=/a,target=/b,format=raw,aio=native"; "native aio with cache")]
+    #[test_case("source=/a,target=/b,format=raw,bus=sata,iothread"; "iothread without virtio")]
+    #[test_case("source=/a,target=/b,format=raw,mount=mnt"; "relative mount")]
+    #[test_case("source=/a,target=/b,format=raw,fstype=ext4"; "fstype without mount")]
+    #[test_case("source=/a,target=/b,format=raw,mount=/m,mkfs=auto"; "mkfs without fstype")]
+    #[test_case("source=/a,target=/b,format=raw,mount=/m,fstype=xfs,mkfs=auto,readonly"; "readonly mkfs")]
+    fn blockdev_invalid(s: &str) {
+        assert!(s.parse::<Blockdev>().is_err(), "{s}");
+    }
+
+    #[test]
+    fn virtiofs_opt_valid() {
+        let opt: VirtiofsOpt =
+            "target=/data,cache=never,thread-pool-size=4,uid-map=0:1000:1,gid-map=0:1000:1"
+                .parse()
+                .unwrap();
+
+        assert_eq!(opt.target, Path::new("/data"));
+        assert_eq!(
+            opt.options.virtiofsd_args(),
+            [
+                "--cache=never",
+                "--translate-uid=map:0:1000:1",
+                "--translate-gid=map:0:1000:1",
+            ]
+        );
+        assert_eq!(opt.options.thread_pool_size, Some(4));
+    }
+
+    #[test_case("cache=never"; "missing target")]
+    #[test_case("target=data"; "relative target")]
+    #[test_case("target=/data,cache=sometimes"; "invalid cache")]
+    #[test_case("target=/data,thread-pool-size=-1"; "negative thread pool size")]
+    #[test_case("target=/data,uid-map=0:1000"; "short id map")]
+    #[test_case("target=/data,gid-map=a:b:c"; "non-numeric id map")]
+    #[test_case("target=/data,foo"; "unknown key")]
+    #[test_case("target=/data,dax=1G"; "dax")]
+    fn virtiofs_opt_invalid(s: &str) {
+        assert!(s.parse::<VirtiofsOpt>().is_err(), "{s}");
+    }
+
+    #[test_case(&["--persistent"], None, &["--persistent"]; "no entrypoint")]
+    #[test_case(&["/entry", "-x", "--persistent"], Some(&["/entry", "-x"]), &["--persistent"]; "recorded entrypoint")]
+    #[test_case(&["/other", "--persistent"], Some(&["/entry"]), &["/other", "--persistent"]; "different entrypoint")]
+    #[test_case(&["/entry", "--", "--persistent"], Some(&["/entry"]), &["--persistent"]; "separator")]
+    #[test_case(&["--", "--persistent"], None, &["--persistent"]; "separator without entrypoint")]
+    #[test_case(&["/other", "--", "--persistent"], Some(&["/entry"]), &["/other", "--", "--persistent"]; "separator after different entrypoint")]
+    #[test_case(&["--password", "--"], None, &["--password", "--"]; "option value")]
+    #[test_case(&["/entry", "--password", "--"], Some(&["/entry"]), &["--password", "--"]; "option value after entrypoint")]
+    fn strip_entrypoint_strips_prefix(
+        args: &[&str],
+        entrypoint: Option<&[&str]>,
+        expected: &[&str],
+    ) {
+        let entrypoint: Option<Vec<String>> =
+            entrypoint.map(|e| e.iter().map(|s| s.to_string()).collect());
+        assert_eq!(strip_entrypoint(args, entrypoint.as_deref()), expected);
+    }
+
+    fn spec_with_args(args: &[&str]) -> oci_spec::runtime::Spec {
+        let mut spec = oci_spec::runtime::Spec::default();
+        let process = oci_spec::runtime::ProcessBuilder::default()
+            .args(args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+            .build()
+            .unwrap();
+        spec.set_process(Some(process));
+        spec
+    }
+
+    #[test_case(&["--blockdev", "source=disk.img,target=/disk.img,format=raw"], "--blockdev"; "blockdev")]
+    #[test_case(&["--scratch-disk", "size=1G,target=/data,persist=data.qcow2"], "--scratch-disk persist="; "scratch disk persist")]
+    #[test_case(&["--install-disk", "size=1G,persist=disk.qcow2"], "--install-disk persist="; "install disk persist")]
+    #[test_case(&["--overlay-dir", "overlays"], "--overlay-dir"; "overlay dir")]
+    fn from_spec_docker_rejects_relative_paths(args: &[&str], option: &str) {
+        let error = CustomOptions::from_spec(
+            &spec_with_args(args),
+            RuntimeEnv::Docker,
+            None,
+            CustomOptionsRaw::default(),
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert_eq!(
+            error,
+            format!(
+                "paths specified using {option} must be absolute when using crun-vm as a Docker runtime"
+            )
+        );
+    }
+
+    #[test_case(&["--scratch-disk", "size=1G,target=/data,persist=/data.qcow2"]; "scratch disk persist")]
+    #[test_case(&["--install-disk", "size=1G,persist=/disk.qcow2"]; "install disk persist")]
+    #[test_case(&["--overlay-dir", "/overlays"]; "overlay dir")]
+    fn from_spec_kubernetes_rejects_host_paths(args: &[&str]) {
+        let error = CustomOptions::from_spec(
+            &spec_with_args(args),
+            RuntimeEnv::Kubernetes,
+            None,
+            CustomOptionsRaw::default(),
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains("not allowed when using crun-vm as a Kubernetes runtime"));
+    }
+
+    #[test_case(&["--scratch-disk", "size=1G,target=data"]; "scratch disk target")]
+    #[test_case(&["--disk-format", "target=disk.img,format=raw"]; "disk format target")]
+    fn from_spec_kubernetes_rejects_relative_paths(args: &[&str]) {
+        let error = CustomOptions::from_spec(
+            &spec_with_args(args),
+            RuntimeEnv::Kubernetes,
+            None,
+            CustomOptionsRaw::default(),
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains("must be absolute when using crun-vm as a Kubernetes runtime"));
+    }
+
+    #[test]
+    fn from_spec_accepts_relative_paths_elsewhere() {
+        let options = CustomOptions::from_spec(
+            &spec_with_args(&["--overlay-dir", "overlays"]),
+            RuntimeEnv::Other,
+            None,
+            CustomOptionsRaw::default(),
+        )
+        .unwrap();
+
+        assert_eq!(options.overlay_dir.as_deref(), Some(Path::new("overlays")));
     }
 }
@@ -7,19 +7,30 @@ use std::path::Path;
 use anyhow::{ensure, Result};
 use xml::writer::XmlEvent;
 
-use crate::commands::create::custom_opts::{CustomOptions, VfioPciMdevUuid};
-use crate::commands::create::Mounts;
+use crate::commands::create::custom_opts::{
+    CustomOptions, DiskBus, FsDriver, VfioPciMdevUuid, VirtiofsOptions,
+};
+use crate::commands::create::{Mounts, VmImageDisks};
+use crate::config::HostConfig;
 use crate::util::{PathExt, SpecExt, VmImageInfo};
 
 pub fn set_up_libvirt_domain_xml(
     spec: &oci_spec::runtime::Spec,
-    vm_image_info: &VmImageInfo,
+    vm_image_disks: &VmImageDisks,
     mounts: &Mounts,
     custom_options: &CustomOptions,
+    host_config: &HostConfig,
 ) -> Result<()> {
     let path = spec.root_path().join("crun-vm/domain.xml");
 
-    generate(&path, spec, vm_image_info, mounts, custom_options)?;
+    generate(
+        &path,
+        spec,
+        vm_image_disks,
+        mounts,
+        custom_options,
+        host_config,
+    )?;
     merge_overlays(&path, &custom_options.merge_libvirt_xml)?;
 
     Ok(())
@@ -28,9 +39,10 @@ pub fn set_up_libvirt_domain_xml(
 fn generate(
     path: impl AsRef<Path>,
     spec: &oci_spec::runtime::Spec,
-    vm_image_info: &VmImageInfo,
+    vm_image_disks: &VmImageDisks,
     mounts: &Mounts,
     custom_options: &CustomOptions,
+    host_config: &HostConfig,
 ) -> Result<()> {
     let mut w = xml::EmitterConfig::new()
         .perform_indent(true)
@@ -40,17 +52,35 @@ fn generate(
         st(w, "name", &[], "domain")?;
 
         se(w, "cpu", &[("mode", "host-model")])?;
-        let vcpus = get_vcpu_count(spec).to_string();
+        let hardware = &vm_image_disks.hardware;
+
+        let vcpus = get_vcpu_count(spec, hardware.vcpus).to_string();
         if let Some(cpu_set) = get_cpu_set(spec) {
             st(w, "vcpu", &[("cpuset", cpu_set.as_str())], vcpus.as_str())?;
         } else {
             st(w, "vcpu", &[], vcpus.as_str())?;
         }
 
-        let memory = get_memory_size(spec).to_string();
+        let iothreads = mounts
+            .block_device
+            .iter()
+            .filter(|dev| dev.options.iothread)
+            .count();
+        if iothreads > 0 {
+            st(w, "iothreads", &[], &iothreads.to_string())?;
+        }
+
+        // the appliance's memory size is only a default, like the one from the image config file
+        // that `custom_options.memory` may already hold
+        let memory = get_memory_size(spec, custom_options.memory.or(hardware.memory)).to_string();
         st(w, "memory", &[("unit", "b")], memory.as_str())?;
 
-        s(w, "os", &[], |w| {
+        let os_attrs: &[_] = if custom_options.uefi {
+            &[("firmware", "efi")]
+        } else {
+            &[]
+        };
+        s(w, "os", os_attrs, |w| {
             st(w, "type", &[("arch", "x86_64"), ("machine", "q35")], "hvm")
         })?;
 
@@ -68,7 +98,10 @@ fn generate(
             )
         })?;
 
-        if !mounts.virtiofs.is_empty() {
+        // vhost-user devices like virtiofs require shared memory
+        if mounts.fs_driver == FsDriver::Virtiofs
+            && (!mounts.virtiofs.is_empty() || !mounts.file.is_empty())
+        {
             s(w, "memoryBacking", &[], |w| {
                 se(w, "source", &[("type", "memfd")])?;
                 se(w, "access", &[("mode", "shared")])?;
@@ -77,7 +110,7 @@ fn generate(
         }
 
         s(w, "devices", &[], |w| {
-            st(w, "emulator", &[], "/usr/bin/qemu-system-x86_64")?;
+            st(w, "emulator", &[], host_config.emulator.as_str())?;
 
             s(w, "serial", &[("type", "pty")], |w| {
                 se(w, "target", &[("port", "0")])
@@ -86,78 +119,229 @@ fn generate(
                 se(w, "target", &[("type", "serial"), ("port", "0")])
             })?;
 
-            let mut next_dev_index = 0;
-            let mut next_dev_name = || {
-                let i = next_dev_index;
-                next_dev_index += 1;
-                format!("vd{}", ('a'..='z').cycle().nth(i).unwrap())
-            };
-
-            s(w, "disk", &[("type", "file"), ("device", "disk")], |w| {
-                se(w, "target", &[("dev", &next_dev_name()), ("bus", "virtio")])?;
+            // lets `crun-vm backup` freeze guest file systems, if the VM runs the QEMU guest agent
+            s(w, "channel", &[("type", "unix")], |w| {
                 se(
                     w,
-                    "driver",
-                    &[("name", "qemu"), ("type", &vm_image_info.format)],
-                )?;
-                se(w, "source", &[("file", vm_image_info.path.as_str())])?;
-                Ok(())
+                    "target",
+                    &[("type", "virtio"), ("name", "org.qemu.guest_agent.0")],
+                )
             })?;
 
+            let mut next_virtio_index = 0;
+            let mut next_sd_index = 0;
+            let mut next_nvme_index = 0;
+            let mut next_dev_name = |bus: DiskBus| {
+                let (prefix, next_index) = match bus {
+                    DiskBus::Virtio => ("vd", &mut next_virtio_index),
+                    DiskBus::Scsi | DiskBus::Sata => ("sd", &mut next_sd_index),
+                    DiskBus::Nvme => ("nvme", &mut next_nvme_index),
+                };
+                let i = *next_index;
+                *next_index += 1;
+                match bus {
+                    DiskBus::Nvme => format!("{prefix}{i}n1"),
+                    _ => format!("{prefix}{}", ('a'..='z').cycle().nth(i).unwrap()),
+                }
+            };
+
+            // When running an installer, try the disk it installs to first, which firmware skips
+            // while it is still blank, and then the installer, so that the VM boots the installed
+            // system once there is one. Otherwise leave the boot order unset, so that the first
+            // disk is booted.
+            let boot_order = |i: usize, is_cdrom: bool| {
+                (vm_image_disks.boot_from_cdrom && i == 0).then_some(if is_cdrom {
+                    "2"
+                } else {
+                    "1"
+                })
+            };
+
+            // the boot disk comes first, followed by any other disks in the VM image
+            let vm_image_bus = hardware.disk_bus.unwrap_or(DiskBus::Virtio);
+            for (i, disk) in vm_image_disks.disks.iter().enumerate() {
+                s(w, "disk", &[("type", "file"), ("device", "disk")], |w| {
+                    se(
+                        w,
+                        "target",
+                        &[
+                            ("dev", &next_dev_name(vm_image_bus)),
+                            ("bus", vm_image_bus.as_str()),
+                        ],
+                    )?;
+                    se(
+                        w,
+                        "driver",
+                        &[("name", "qemu"), ("type", driver_type(&disk.info.format))],
+                    )?;
+                    image_source(w, &disk.info, disk.secret)?;
+                    if let Some((backing_info, backing_secret)) = &disk.encrypted_backing {
+                        s(w, "backingStore", &[("type", "file")], |w| {
+                            se(w, "format", &[("type", driver_type(&backing_info.format))])?;
+                            image_source(w, backing_info, Some(backing_secret))?;
+                            se(w, "backingStore", &[])
+                        })?;
+                    }
+                    if let Some(order) = boot_order(i, false) {
+                        se(w, "boot", &[("order", order)])?;
+                    }
+                    Ok(())
+                })?;
+            }
+
+            for (i, vm_image_info) in vm_image_disks.cdroms.iter().enumerate() {
+                s(w, "disk", &[("type", "file"), ("device", "cdrom")], |w| {
+                    se(
+                        w,
+                        "target",
+                        &[("dev", &next_dev_name(DiskBus::Sata)), ("bus", "sata")],
+                    )?;
+                    se(w, "driver", &[("name", "qemu"), ("type", "raw")])?;
+                    se(w, "source", &[("file", vm_image_info.path.as_str())])?;
+                    se(w, "readonly", &[])?;
+                    if let Some(order) = boot_order(i, true) {
+                        se(w, "boot", &[("order", order)])?;
+                    }
+                    Ok(())
+                })?;
+            }
+
+            let mut next_iothread = 1;
+
             for (i, dev) in mounts.block_device.iter().enumerate() {
                 let typ = if dev.is_regular_file { "file" } else { "block" };
                 let source_attr = if dev.is_regular_file { "file" } else { "dev" };
 
+                let mut driver_attrs =
+                    vec![("name", "qemu".to_string()), ("type", dev.format.clone())];
+                if let Some(cache) = &dev.options.cache {
+                    driver_attrs.push(("cache", cache.clone()));
+                }
+                if let Some(aio) = &dev.options.aio {
+                    driver_attrs.push(("io", aio.clone()));
+                }
+                if let Some(discard) = &dev.options.discard {
+                    driver_attrs.push(("discard", discard.clone()));
+                }
+                if dev.options.iothread {
+                    driver_attrs.push(("iothread", next_iothread.to_string()));
+                    next_iothread += 1;
+                }
+                let driver_attrs: Vec<(&str, &str)> =
+                    driver_attrs.iter().map(|(k, v)| (*k, v.as_str())).collect();
+
+                let bus = dev.options.bus;
+
                 s(w, "disk", &[("type", typ), ("device", "disk")], |w| {
-                    se(w, "target", &[("dev", &next_dev_name()), ("bus", "virtio")])?;
-                    se(w, "driver", &[("name", "qemu"), ("type", &dev.format)])?;
+                    se(
+                        w,
+                        "target",
+                        &[("dev", &next_dev_name(bus)), ("bus", bus.as_str())],
+                    )?;
+                    se(w, "driver", &driver_attrs)?;
                     se(
                         w,
                         "source",
                         &[(source_attr, dev.path_in_container.as_str())],
                     )?;
-                    if dev.readonly {
+                    if dev.options.readonly {
                         se(w, "readonly", &[])?;
                     }
-                    st(w, "serial", &[], &format!("crun-vm-block-{i}"))?;
+                    st(w, "serial", &[], &dev.serial(i))?;
                     Ok(())
                 })?;
             }
 
+            if vm_image_bus == DiskBus::Scsi
+                || mounts
+                    .block_device
+                    .iter()
+                    .any(|dev| dev.options.bus == DiskBus::Scsi)
+            {
+                // appliances may not have drivers for virtio-scsi
+                let model = hardware.scsi_model.as_deref().unwrap_or("virtio-scsi");
+                se(w, "controller", &[("type", "scsi"), ("model", model)])?;
+            }
+
             s(w, "disk", &[("type", "file"), ("device", "disk")], |w| {
                 se(
                     w,
                     "source",
                     &[("file", "/crun-vm/first-boot/cloud-init.iso")],
                 )?;
-                se(w, "target", &[("dev", &next_dev_name()), ("bus", "virtio")])?;
+                se(
+                    w,
+                    "target",
+                    &[("dev", &next_dev_name(DiskBus::Virtio)), ("bus", "virtio")],
+                )?;
                 Ok(())
             })?;
 
             s(w, "interface", &[("type", "user")], |w| {
                 se(w, "backend", &[("type", "passt")])?;
-                se(w, "model", &[("type", "virtio")])?;
+                let model = hardware.nic_model.as_deref().unwrap_or("virtio");
+                se(w, "model", &[("type", model)])?;
                 se(w, "portForward", &[("proto", "tcp")])?;
                 se(w, "portForward", &[("proto", "udp")])?;
                 Ok(())
             })?;
 
+            let mut add_filesystem =
+                |path: &str,
+                 tag: &str,
+                 readonly: bool,
+                 virtiofsd: &str,
+                 options: &VirtiofsOptions| {
+                    let attrs: &[(&str, &str)] = match mounts.fs_driver {
+                        FsDriver::Virtiofs => &[("type", "mount")],
+                        FsDriver::NineP => &[("type", "mount"), ("accessmode", "passthrough")],
+                    };
+
+                    s(w, "filesystem", attrs, |w| {
+                        match mounts.fs_driver {
+                            FsDriver::Virtiofs => {
+                                se(w, "driver", &[("type", "virtiofs")])?;
+                                s(w, "binary", &[("path", virtiofsd), ("xattr", "on")], |w| {
+                                    se(w, "sandbox", &[("mode", "chroot")])?;
+                                    if let Some(size) = options.thread_pool_size {
+                                        se(
+                                            w,
+                                            "thread_pool",
+                                            &[("size", size.to_string().as_str())],
+                                        )?;
+                                    }
+                                    Ok(())
+                                })?;
+                            }
+                            FsDriver::NineP => se(w, "driver", &[("type", "path")])?,
+                        }
+                        se(w, "source", &[("dir", path)])?;
+                        se(w, "target", &[("dir", tag)])?;
+                        if readonly {
+                            se(w, "readonly", &[])?;
+                        }
+                        Ok(())
+                    })
+                };
+
             for (i, mount) in mounts.virtiofs.iter().enumerate() {
-                let path = mount.path_in_container.as_str();
-                let tag = format!("virtiofs-{}", i);
+                add_filesystem(
+                    mount.path_in_container.as_str(),
+                    &format!("virtiofs-{}", i),
+                    mount.readonly,
+                    mount.virtiofsd.as_str(),
+                    &mount.options,
+                )?;
+            }
 
-                s(w, "filesystem", &[("type", "mount")], |w| {
-                    se(w, "driver", &[("type", "virtiofs")])?;
-                    s(
-                        w,
-                        "binary",
-                        &[("path", "/crun-vm/virtiofsd"), ("xattr", "on")],
-                        |w| se(w, "sandbox", &[("mode", "chroot")]),
-                    )?;
-                    se(w, "source", &[("dir", path)])?;
-                    se(w, "target", &[("dir", &tag)])?;
-                    Ok(())
-                })?;
+            for share in mounts.file_shares() {
+                add_filesystem(
+                    share.path_in_container,
+                    share.tag,
+                    share.readonly,
+                    "/crun-vm/virtiofsd",
+                    &VirtiofsOptions::default(),
+                )?;
             }
 
             for address in &custom_options.vfio_pci {
@@ -284,6 +468,32 @@ fn merge_overlays(base_path: impl AsRef<Path>, overlay_paths: &[impl AsRef<Path>
     save(&base_path, &base_root)
 }
 
+/// Raw LUKS images are reported by qemu-img as having format "luks", but libvirt expects the
+/// "raw" format plus an `<encryption>` element.
+fn driver_type(format: &str) -> &str {
+    match format {
+        "luks" => "raw",
+        format => format,
+    }
+}
+
+// VM image source, along with the libvirt secret with its passphrase if it is encrypted
+fn image_source(
+    w: &mut xml::EventWriter<File>,
+    info: &VmImageInfo,
+    secret: Option<&str>,
+) -> xml::writer::Result<()> {
+    let attrs = [("file", info.path.as_str())];
+    match secret {
+        Some(uuid) => s(w, "source", &attrs, |w| {
+            s(w, "encryption", &[("format", "luks")], |w| {
+                se(w, "secret", &[("type", "passphrase"), ("uuid", uuid)])
+            })
+        }),
+        None => se(w, "source", &attrs),
+    }
+}
+
 // section
 fn s(
     w: &mut xml::EventWriter<File>,
@@ -322,7 +532,7 @@ fn se(
     s(w, name, attrs, |_w| Ok(()))
 }
 
-fn get_vcpu_count(spec: &oci_spec::runtime::Spec) -> u64 {
+fn get_vcpu_count(spec: &oci_spec::runtime::Spec, default: Option<u64>) -> u64 {
     let vcpu_count = (|| {
         let linux_cpu = spec
             .linux()
@@ -346,10 +556,12 @@ fn get_vcpu_count(spec: &oci_spec::runtime::Spec) -> u64 {
             .checked_div(period)
     })();
 
-    vcpu_count.unwrap_or_else(|| num_cpus::get().try_into().unwrap())
+    vcpu_count
+        .or(default)
+        .unwrap_or_else(|| num_cpus::get().try_into().unwrap())
 }
 
-fn get_memory_size(spec: &oci_spec::runtime::Spec) -> u64 {
+fn get_memory_size(spec: &oci_spec::runtime::Spec, default: Option<u64>) -> u64 {
     let memory_size: Option<u64> = (|| {
         spec.linux()
             .as_ref()?
@@ -362,7 +574,7 @@ fn get_memory_size(spec: &oci_spec::runtime::Spec) -> u64 {
             .ok()
     })();
 
-    memory_size.unwrap_or_else(|| 2u64.pow(31)) // default to 2 GiB
+    memory_size.or(default).unwrap_or_else(|| 2u64.pow(31)) // default to 2 GiB
 }
 
 fn get_cpu_set(spec: &oci_spec::runtime::Spec) -> Option<String> {
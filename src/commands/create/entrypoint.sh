@@ -5,6 +5,31 @@ trap 'exit 143' SIGTERM
 
 set -o errexit -o pipefail -o nounset
 
+# hold the lock on the --persistent VM image or on the VM image overlays for as
+# long as the container runs; `crun-vm create` only holds it while setting up the
+# container, so this is what catches containers created at the same time
+if [[ -e /crun-vm/image.lock ]]; then
+    exec {lock_fd}</crun-vm/image.lock
+    if ! flock --exclusive --nonblock "$lock_fd"; then
+        echo "VM image or overlay is already in use by another container" >&2
+        exit 1
+    fi
+fi
+
+# grow the --persistent VM image now that we hold its lock, and only once
+if [[ -e /crun-vm/resize-image.sh ]]; then
+    bash /crun-vm/resize-image.sh
+    rm /crun-vm/resize-image.sh
+fi
+
+# keep the cache entries holding decompressed VM images and converted appliances
+# from being evicted while the container runs
+for cache_lock in /crun-vm/cache-locks/*; do
+    [[ -e "$cache_lock" ]] || continue
+    exec {cache_lock_fd}<"$cache_lock"
+    flock --shared "$cache_lock_fd"
+done
+
 mkdir -p \
     /etc/libvirt \
     /tmp \
@@ -30,20 +55,16 @@ virtlogd --daemon
 if command -v virtqemud >/dev/null; then
     virtqemud --daemon
     socket=/run/libvirt/virtqemud-sock
+    if [[ -e /crun-vm/secrets ]]; then
+        virtsecretd --daemon
+        secret_uri=secret+unix:///system?socket=/run/libvirt/virtsecretd-sock
+    fi
 else
     libvirtd --daemon
     socket=/run/libvirt/libvirt-sock
+    secret_uri=qemu+unix:///session?socket=$socket
 fi
 
-# libvirt doesn't let us pass --modcaps to virtiofsd (which we use to avoid
-# having virtiofsd unsuccessfully attempt to acquire additional capabilities),
-# so we tell libvirt to use the /crun-vm/virtiofsd script below.
-cat <<'EOF' >/crun-vm/virtiofsd
-#!/bin/bash
-/usr/libexec/virtiofsd --modcaps=-mknod:-setfcap "$@"
-EOF
-chmod +x /crun-vm/virtiofsd
-
 # When running under Docker or rootful Podman, passt will realize that it is
 # running as *actual* root and will switch to being user nobody, but this will
 # make it fail to create its PID file because its directory was created by
@@ -73,6 +94,36 @@ function __bg_ensure_tty() {
 
 virsh=( virsh --connect "qemu+unix:///session?socket=$socket" --quiet )
 
+# Encrypted overlays are created with a random key that only ever exists in the
+# /crun-vm/keys tmpfs, and libvirt is given the passphrases of encrypted VM
+# images and overlays as ephemeral secrets. The key is lost when the container
+# stops, so rather than silently recreating the overlays and discarding what the
+# VM wrote to them, refuse to start the container again.
+if [[ -e /crun-vm/encrypted-overlays.sh ]]; then
+    if [[ -e /crun-vm/encrypted-overlays.created ]]; then
+        echo "containers using --encrypt-overlay can't be restarted, since the key to" \
+            "their overlays was lost when they stopped; create a new container instead" >&2
+        exit 1
+    fi
+    head -c 32 /dev/urandom | base64 --wrap=0 > /crun-vm/keys/overlay.key
+    bash /crun-vm/encrypted-overlays.sh /crun-vm/keys/overlay.key
+    touch /crun-vm/encrypted-overlays.created
+fi
+
+if [[ -e /crun-vm/secrets ]]; then
+    while read -r uuid key_path; do
+        cat <<EOF >/crun-vm/keys/secret.xml
+<secret ephemeral='yes' private='yes'><uuid>$uuid</uuid></secret>
+EOF
+        # drop any trailing newline, as in files created with `echo`
+        printf '%s' "$(<"$key_path")" >/crun-vm/keys/passphrase
+        virsh --connect "$secret_uri" --quiet secret-define /crun-vm/keys/secret.xml
+        virsh --connect "$secret_uri" --quiet secret-set-value "$uuid" \
+            --file /crun-vm/keys/passphrase --plain
+        rm /crun-vm/keys/passphrase
+    done </crun-vm/secrets
+fi
+
 # If our container was stopped and is being restarted, the domain may still be
 # defined from the previous run, which would cause `virsh define` below to fail,
 # so we first undefine it.
@@ -7,14 +7,27 @@ use std::process::Command;
 
 use anyhow::{bail, ensure, Context, Result};
 
+use crate::commands::create::custom_opts::{DiskBus, FsDriver};
 use crate::commands::create::Mounts;
 use crate::util::PathExt;
 
+/// A block device whose filesystem is mounted in the guest.
+struct BlockDeviceFsMount<'a> {
+    device: PathBuf,
+    path_in_guest: &'a Path,
+    fstype: &'a str,
+    mkfs: bool,
+    readonly: bool,
+}
+
 pub struct FirstBootConfig<'a> {
     pub hostname: Option<&'a str>,
     pub container_public_key: &'a str,
     pub password: Option<&'a str>,
     pub mounts: &'a Mounts,
+    /// Whether the root partition and file system should be grown to fill the disk. Only applies to
+    /// cloud-init, as CoreOS-based VMs using Ignition grow them by themselves on first boot.
+    pub grow_root: bool,
 }
 
 impl FirstBootConfig<'_> {
@@ -93,9 +106,51 @@ impl FirstBootConfig<'_> {
             chpasswd.insert("expire".into(), false.into());
         }
 
+        // grow root partition and file system
+
+        if self.grow_root {
+            user_data_mapping
+                .entry("growpart".into())
+                .or_insert_with(|| {
+                    let mut growpart = serde_yaml::Mapping::new();
+                    growpart.insert("mode".into(), "auto".into());
+                    growpart.insert("devices".into(), vec!["/"].into());
+                    growpart.into()
+                });
+
+            user_data_mapping
+                .entry("resize_rootfs".into())
+                .or_insert(true.into());
+        }
+
         // adjust mounts
 
-        if !self.mounts.virtiofs.is_empty() || !self.mounts.tmpfs.is_empty() {
+        let block_device_mounts = self.get_block_device_mounts();
+
+        if block_device_mounts.iter().any(|m| m.mkfs) {
+            let fs_setup = match user_data_mapping
+                .entry("fs_setup".into())
+                .or_insert_with(|| serde_yaml::Value::Sequence(vec![]))
+            {
+                serde_yaml::Value::Sequence(v) => v,
+                _ => bail!("invalid user-data file"),
+            };
+
+            for mount in block_device_mounts.iter().filter(|m| m.mkfs) {
+                let mut mapping = serde_yaml::Mapping::new();
+                mapping.insert("device".into(), mount.device.as_str().into());
+                mapping.insert("filesystem".into(), mount.fstype.into());
+                // only create a filesystem if the disk doesn't already have one
+                mapping.insert("overwrite".into(), false.into());
+
+                fs_setup.push(mapping.into());
+            }
+        }
+
+        if !self.mounts.virtiofs.is_empty()
+            || !self.mounts.tmpfs.is_empty()
+            || !block_device_mounts.is_empty()
+        {
             let mounts: &mut Vec<serde_yaml::Value> = match user_data_mapping
                 .entry("mounts".into())
                 .or_insert_with(|| serde_yaml::Value::Sequence(vec![]))
@@ -104,17 +159,77 @@ impl FirstBootConfig<'_> {
                 _ => bail!("invalid user-data file"),
             };
 
-            let mut add_mount = |typ: &str, tag: &str, path_in_guest: &Path| {
+            let mut add_mount = |typ: &str, tag: &str, path_in_guest: &Path, options: &str| {
                 let path_in_guest = path_in_guest.as_str();
-                mounts.push(vec![&tag, path_in_guest, typ, "defaults", "0", "0"].into());
+                mounts.push(vec![&tag, path_in_guest, typ, options, "0", "0"].into());
             };
 
             for (i, mount) in self.mounts.virtiofs.iter().enumerate() {
-                add_mount("virtiofs", &format!("virtiofs-{i}"), &mount.path_in_guest);
+                add_mount(
+                    self.mounts.fs_driver.as_str(),
+                    &format!("virtiofs-{i}"),
+                    &mount.path_in_guest,
+                    share_mount_options(self.mounts.fs_driver, mount.readonly),
+                );
             }
 
             for mount in &self.mounts.tmpfs {
-                add_mount("tmpfs", "tmpfs", &mount.path_in_guest);
+                add_mount("tmpfs", "tmpfs", &mount.path_in_guest, "defaults");
+            }
+
+            for mount in &block_device_mounts {
+                add_mount(
+                    mount.fstype,
+                    mount.device.as_str(),
+                    mount.path_in_guest,
+                    mount_options(mount.readonly),
+                );
+            }
+        }
+
+        // bind mount regular files
+
+        if !self.mounts.file.is_empty() {
+            let bootcmd = match user_data_mapping
+                .entry("bootcmd".into())
+                .or_insert_with(|| serde_yaml::Value::Sequence(vec![]))
+            {
+                serde_yaml::Value::Sequence(v) => v,
+                _ => bail!("invalid user-data file"),
+            };
+
+            // We can't use the mounts module for these, as it would create the mount points as
+            // directories. Commands in bootcmd run on every boot, so the mounts persist.
+
+            for share in self.mounts.file_shares() {
+                bootcmd.push(vec!["mkdir", "-p", share.path_in_guest].into());
+                bootcmd.push(
+                    vec![
+                        "mount",
+                        "-t",
+                        self.mounts.fs_driver.as_str(),
+                        "-o",
+                        share_mount_options(self.mounts.fs_driver, share.readonly),
+                        share.tag,
+                        share.path_in_guest,
+                    ]
+                    .into(),
+                );
+            }
+
+            for mount in &self.mounts.file {
+                bootcmd.push(
+                    vec![
+                        "sh",
+                        "-c",
+                        r#"mkdir -p "$(dirname "$2")" && touch "$2" && mount -o "$3" "$1" "$2""#,
+                        "sh",
+                        mount.path_in_share.as_str(),
+                        mount.path_in_guest.as_str(),
+                        bind_mount_options(mount.readonly),
+                    ]
+                    .into(),
+                );
             }
         }
 
@@ -366,6 +481,28 @@ impl FirstBootConfig<'_> {
             }));
         }
 
+        // create filesystems on block devices
+
+        let block_device_mounts = self.get_block_device_mounts();
+
+        if block_device_mounts.iter().any(|m| m.mkfs) {
+            let filesystems = match storage
+                .entry("filesystems")
+                .or_insert_with(|| serde_json::json!([]))
+            {
+                serde_json::Value::Array(filesystems) => filesystems,
+                _ => bail!("invalid config file"),
+            };
+
+            for mount in block_device_mounts.iter().filter(|m| m.mkfs) {
+                filesystems.push(serde_json::json!({
+                    "device": mount.device.as_str(),
+                    "format": mount.fstype,
+                    "wipeFilesystem": false,
+                }));
+            }
+        }
+
         // adjust mounts
 
         let systemd = match user_data_mapping
@@ -384,14 +521,11 @@ impl FirstBootConfig<'_> {
             _ => bail!("invalid config file"),
         };
 
-        let mut add_mount = |typ: &str, tag: &str, path_in_guest: &Path| {
+        let mut add_mount = |typ: &str, tag: &str, path_in_guest: &Path, options: &str| {
             let path_in_guest = path_in_guest.as_str();
 
             // systemd insists on this unit file name format
-            let systemd_unit_file_name = format!(
-                "{}.mount",
-                path_in_guest.trim_matches('/').replace('/', "-")
-            );
+            let systemd_unit_file_name = format!("{}.mount", systemd_escape_path(path_in_guest));
 
             let systemd_unit = format!(
                 "\
@@ -399,6 +533,7 @@ impl FirstBootConfig<'_> {
                 What={tag}\n\
                 Where={path_in_guest}\n\
                 Type={typ}\n\
+                Options={options}\n\
                 \n\
                 [Install]\n\
                 WantedBy=local-fs.target\n\
@@ -413,11 +548,46 @@ impl FirstBootConfig<'_> {
         };
 
         for (i, mount) in self.mounts.virtiofs.iter().enumerate() {
-            add_mount("virtiofs", &format!("virtiofs-{i}"), &mount.path_in_guest);
+            add_mount(
+                self.mounts.fs_driver.as_str(),
+                &format!("virtiofs-{i}"),
+                &mount.path_in_guest,
+                share_mount_options(self.mounts.fs_driver, mount.readonly),
+            );
         }
 
         for mount in &self.mounts.tmpfs {
-            add_mount("tmpfs", "tmpfs", &mount.path_in_guest);
+            add_mount("tmpfs", "tmpfs", &mount.path_in_guest, "defaults");
+        }
+
+        for mount in &block_device_mounts {
+            add_mount(
+                mount.fstype,
+                mount.device.as_str(),
+                mount.path_in_guest,
+                mount_options(mount.readonly),
+            );
+        }
+
+        if !self.mounts.file.is_empty() {
+            for share in self.mounts.file_shares() {
+                add_mount(
+                    self.mounts.fs_driver.as_str(),
+                    share.tag,
+                    Path::new(share.path_in_guest),
+                    share_mount_options(self.mounts.fs_driver, share.readonly),
+                );
+            }
+
+            // systemd creates the mount point as a regular file, since the source is one
+            for mount in &self.mounts.file {
+                add_mount(
+                    "none",
+                    mount.path_in_share.as_str(),
+                    &mount.path_in_guest,
+                    bind_mount_options(mount.readonly),
+                );
+            }
         }
 
         // generate file
@@ -435,22 +605,46 @@ impl FirstBootConfig<'_> {
 
         for (i, dev) in self.mounts.block_device.iter().enumerate() {
             if dev.path_in_guest.parent() != Some(Path::new("/dev")) {
-                let target = PathBuf::from(format!("/dev/disk/by-id/virtio-crun-vm-block-{i}"));
-                symlinks.push((dev.path_in_guest.as_path(), target));
+                symlinks.push((dev.path_in_guest.as_path(), dev.path_in_guest_by_id(i)));
             }
         }
 
         symlinks
     }
 
+    fn get_block_device_mounts(&self) -> Vec<BlockDeviceFsMount<'_>> {
+        let mut mounts = Vec::new();
+
+        for (i, dev) in self.mounts.block_device.iter().enumerate() {
+            if let Some(path_in_guest) = &dev.options.mount {
+                mounts.push(BlockDeviceFsMount {
+                    device: dev.path_in_guest_by_id(i),
+                    path_in_guest,
+                    fstype: dev.options.fstype.as_deref().unwrap_or("auto"),
+                    mkfs: dev.options.mkfs,
+                    readonly: dev.options.readonly,
+                });
+            }
+        }
+
+        mounts
+    }
+
     fn get_block_device_udev_rules(&self) -> Option<String> {
         let mut rules = String::new();
 
         for (i, dev) in self.mounts.block_device.iter().enumerate() {
             if dev.path_in_guest.parent() == Some(Path::new("/dev")) {
+                // udev only sets ID_SERIAL_SHORT for non-virtio disks
+                let serial_key = match dev.options.bus {
+                    DiskBus::Virtio => "ID_SERIAL",
+                    _ => "ID_SERIAL_SHORT",
+                };
+
                 rules.push_str(&format!(
-                    "ENV{{ID_SERIAL}}==\"crun-vm-block-{}\", SYMLINK+=\"{}\"\n",
-                    i,
+                    "ENV{{{}}}==\"{}\", SYMLINK+=\"{}\"\n",
+                    serial_key,
+                    dev.serial(i),
                     dev.path_in_guest.file_name().unwrap().as_str(),
                 ));
             }
@@ -463,3 +657,69 @@ impl FirstBootConfig<'_> {
         }
     }
 }
+
+fn bind_mount_options(readonly: bool) -> &'static str {
+    if readonly {
+        "bind,ro"
+    } else {
+        "bind"
+    }
+}
+
+/// Escapes an absolute path for use in a systemd unit name, like `systemd-escape --path`.
+fn systemd_escape_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+
+    if path.is_empty() {
+        return "-".to_string();
+    }
+
+    let mut escaped = String::new();
+
+    for (i, b) in path.bytes().enumerate() {
+        match b {
+            b'/' => escaped.push('-'),
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b':' || b == b'_' || b == b'.' => {
+                escaped.push(b as char)
+            }
+            b => escaped.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+
+    escaped
+}
+
+fn mount_options(readonly: bool) -> &'static str {
+    if readonly {
+        "ro"
+    } else {
+        "defaults"
+    }
+}
+
+/// Options for mounting a virtiofs or 9p share in the guest.
+fn share_mount_options(fs_driver: FsDriver, readonly: bool) -> &'static str {
+    match fs_driver {
+        FsDriver::Virtiofs => mount_options(readonly),
+        FsDriver::NineP if readonly => "trans=virtio,version=9p2000.L,ro",
+        FsDriver::NineP => "trans=virtio,version=9p2000.L",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("/", "-"; "root")]
+    #[test_case("/mnt/data", "mnt-data"; "nested")]
+    #[test_case("/mnt/data/", "mnt-data"; "trailing slash")]
+    #[test_case("/.hidden/a.b", "\\x2ehidden-a.b"; "leading dot")]
+    #[test_case("/my dir/x-y", "my\\x20dir-x\\x2dy"; "special characters")]
+    #[test_case("/a:b_c", "a:b_c"; "allowed punctuation")]
+    fn systemd_escape_path_matches_systemd_escape(path: &str, expected: &str) {
+        assert_eq!(systemd_escape_path(path), expected);
+    }
+}
@@ -1,26 +1,36 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+mod appliance;
 mod custom_opts;
 mod domain;
 mod first_boot;
 mod runtime_env;
 
 use std::fs::{self, Permissions};
-use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command};
 
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use nix::sys::stat::{major, makedev, minor, mknod, Mode, SFlag};
+use serde::{Deserialize, Serialize};
 
-use crate::commands::create::custom_opts::CustomOptions;
+use crate::commands::create::appliance::{import_appliance, is_tar_archive, ApplianceHardware};
+pub use crate::commands::create::custom_opts::CustomOptionsRaw;
+use crate::commands::create::custom_opts::{
+    CustomOptions, DiskBus, DiskOptions, FsDriver, InstallDisk, VirtiofsOptions,
+};
 use crate::commands::create::domain::set_up_libvirt_domain_xml;
 use crate::commands::create::first_boot::FirstBootConfig;
 use crate::commands::create::runtime_env::RuntimeEnv;
+use crate::config::HostConfig;
 use crate::crun::crun_create;
 use crate::util::{
-    bind_mount_dir_with_different_context, bind_mount_file, create_overlay_vm_image,
-    find_single_file_in_dirs, set_file_context, PathExt, SpecExt, VmImageInfo,
+    bind_mount_dir_with_different_context, bind_mount_file, create_empty_vm_image,
+    create_overlay_vm_image, decompress_file, evict_cache_entries, find_files_in_dirs,
+    get_decompressor, get_file_digest, get_qcow2_backing_file, is_iso_image, lock_cache_entry,
+    parse_sha256_digest, remove_dir_all_if_exists, set_file_context, shell_quote, try_lock_file,
+    unmount_if_mounted, CacheEntryLock, PathExt, Rollback, SpecExt, VmImageInfo,
 };
 
 pub fn create(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Create) -> Result<()> {
@@ -29,23 +39,58 @@ pub fn create(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Create) -
     let mut spec = oci_spec::runtime::Spec::load(&config_path)?;
     let original_root_path = spec.root_path().clone();
 
+    let host_config = HostConfig::load()?;
     let runtime_env = RuntimeEnv::current(&spec, &original_root_path)?;
-    let custom_options = CustomOptions::from_spec(&spec, runtime_env)?;
+    let image_options = load_vm_image_config(&original_root_path)?;
+    let entrypoint = runtime_env.entrypoint(&spec, &args.bundle)?;
+    let custom_options = CustomOptions::from_spec(
+        &spec,
+        runtime_env,
+        entrypoint.as_deref(),
+        image_options.with_defaults(host_config.options.clone()),
+    )?;
 
-    set_up_container_root(&mut spec, &args.bundle, &custom_options)?;
-    let base_vm_image_info =
-        set_up_vm_image(&spec, &args.bundle, &original_root_path, &custom_options)?;
+    // undo side effects outside of the container root if anything below fails, so that no stale
+    // mounts are left behind
+    let mut rollback = Rollback::default();
+
+    let original_config = fs::read(&config_path)?;
+    rollback.push({
+        let config_path = config_path.clone();
+        move || Ok(fs::write(config_path, original_config)?)
+    });
 
-    let mut mounts = Mounts::default();
-    set_up_mounts(&mut spec, &mut mounts)?;
+    set_up_container_root(&mut spec, &args.bundle, &custom_options, &host_config)?;
+    let vm_image_disks = set_up_vm_image(
+        &mut spec,
+        &args.bundle,
+        &args.container_id,
+        &original_root_path,
+        &custom_options,
+        &host_config,
+        &mut rollback,
+    )?;
+
+    let mut mounts = Mounts {
+        fs_driver: get_fs_driver(&custom_options, &host_config),
+        ..Default::default()
+    };
+    set_up_mounts(&mut spec, &mut mounts, &custom_options)?;
     set_up_devices(&mut spec, &mut mounts)?;
     set_up_blockdevs(&mut spec, &mut mounts, &custom_options)?;
+    set_up_scratch_disks(&mut spec, &mut mounts, &custom_options, &mut rollback)?;
 
     set_up_extra_container_mounts_and_devices(&mut spec)?;
     set_up_security(&mut spec);
 
     set_up_first_boot_config(&spec, &mounts, &custom_options, runtime_env)?;
-    set_up_libvirt_domain_xml(&spec, &base_vm_image_info, &mounts, &custom_options)?;
+    set_up_libvirt_domain_xml(
+        &spec,
+        &vm_image_disks,
+        &mounts,
+        &custom_options,
+        &host_config,
+    )?;
 
     adjust_container_resources(&mut spec);
 
@@ -54,6 +99,8 @@ pub fn create(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Create) -
 
     crun_create(global_args, args)?; // actually create container
 
+    rollback.commit();
+
     Ok(())
 }
 
@@ -61,12 +108,13 @@ fn set_up_container_root(
     spec: &mut oci_spec::runtime::Spec,
     bundle_path: &Path,
     custom_options: &CustomOptions,
+    host_config: &HostConfig,
 ) -> Result<()> {
     // create root directory
 
     spec.set_root(Some(
         oci_spec::runtime::RootBuilder::default()
-            .path(bundle_path.join("crun-vm-root"))
+            .path(bundle_path.join(ROOT_DIR_NAME))
             .readonly(false)
             .build()
             .unwrap(),
@@ -91,6 +139,21 @@ fn set_up_container_root(
     fs::write(&entrypoint_path, ENTRYPOINT_BYTES)?;
     fs::set_permissions(&entrypoint_path, Permissions::from_mode(0o555))?;
 
+    // libvirt doesn't let us pass --modcaps to virtiofsd (which we use to avoid having virtiofsd
+    // unsuccessfully attempt to acquire additional capabilities), so we tell libvirt to use this
+    // wrapper script instead.
+
+    let virtiofsd_path = spec.root_path().join("crun-vm/virtiofsd");
+
+    fs::write(
+        &virtiofsd_path,
+        format!(
+            "#!/bin/bash\nexec {} --modcaps=-mknod:-setfcap \"$@\"\n",
+            shell_quote(host_config.virtiofsd.as_str()),
+        ),
+    )?;
+    fs::set_permissions(&virtiofsd_path, Permissions::from_mode(0o555))?;
+
     let command = match custom_options.print_libvirt_xml {
         true => vec!["cat", "/crun-vm/domain.xml"],
         false => vec!["/crun-vm/entrypoint.sh"],
@@ -107,93 +170,1038 @@ fn set_up_container_root(
     Ok(())
 }
 
+/// Name of the directory in the bundle that we use as the container's root.
+pub const ROOT_DIR_NAME: &str = "crun-vm-root";
+
+/// Where in the container the directory containing the VM image is exposed.
+pub const VM_IMAGE_DIR_PATH_IN_CONTAINER: &str = "crun-vm/image";
+
+/// Where in the container the decompressed versions of compressed VM images and the disks of
+/// appliances are exposed, each in a subdirectory named after the image's index.
+pub const VM_IMAGE_CACHE_DIR_PATH_IN_CONTAINER: &str = "crun-vm/image-cache";
+
+/// Name of the private directory used for the VM image's overlayfs mount, or of a symlink to it if
+/// it is outside the bundle.
+pub const VM_IMAGE_PRIVATE_DIR_NAME: &str = "crun-vm-vm-image-overlayfs";
+
+/// Where in the container we record the VM image overlays, for `commit`.
+pub const VM_IMAGE_OVERLAYS_FILE_PATH_IN_CONTAINER: &str = "crun-vm/image-overlays.json";
+
+/// tmpfs in the container holding key material, which must never be written to disk.
+const KEYS_DIR_PATH_IN_CONTAINER: &str = "/crun-vm/keys";
+
+/// UUIDs of the libvirt secrets holding the passphrases of encrypted VM images and of encrypted
+/// overlays, respectively.
+const VM_IMAGE_SECRET_UUID: &str = "5ad83c9d-6b6b-4b53-9a2e-3a7c4cba3f51";
+const OVERLAY_SECRET_UUID: &str = "0d2b9a5e-1f4c-4c1e-8a36-9d3f0e7b6c42";
+
+// where inside the container to look for the VM image and its optional config file
+const VM_IMAGE_SEARCH_PATHS: [&str; 2] = ["./", "disk/"];
+
+// names of the optional config file that may accompany the VM image
+const VM_IMAGE_CONFIG_FILE_NAMES: [&str; 3] = ["crun-vm.yaml", "crun-vm.yml", "crun-vm.json"];
+
+/// Loads the defaults for custom options set in the config file accompanying the VM image, if any.
+fn load_vm_image_config(original_root_path: &Path) -> Result<CustomOptionsRaw> {
+    let mut config_path: Option<PathBuf> = None;
+
+    for dir_path in VM_IMAGE_SEARCH_PATHS.map(|p| original_root_path.join(p)) {
+        for file_name in VM_IMAGE_CONFIG_FILE_NAMES {
+            let path = dir_path.join(file_name);
+            if path.is_file() {
+                ensure!(
+                    config_path.is_none(),
+                    "more than one VM image config file found"
+                );
+                config_path = Some(path);
+            }
+        }
+    }
+
+    match config_path {
+        Some(path) => CustomOptionsRaw::from_image_config_file(&path, original_root_path),
+        None => Ok(CustomOptionsRaw::default()),
+    }
+}
+
 fn set_up_vm_image(
-    spec: &oci_spec::runtime::Spec,
+    spec: &mut oci_spec::runtime::Spec,
     bundle_path: &Path,
+    container_id: &str,
     original_root_path: &Path,
     custom_options: &CustomOptions,
-) -> Result<VmImageInfo> {
-    // where inside the container to look for the VM image
-    const VM_IMAGE_SEARCH_PATHS: [&str; 2] = ["./", "disk/"];
-
-    // docker may add these files to the root of the container
-    const FILES_TO_IGNORE: [&str; 2] = [".dockerinit", ".dockerenv"];
+    host_config: &HostConfig,
+    rollback: &mut Rollback,
+) -> Result<VmImageDisks> {
+    let vm_image_paths_in_host =
+        find_vm_image_files(original_root_path, custom_options, &host_config.cache_dir)?;
+    let vm_image_dir_path = vm_image_paths_in_host[0].parent().unwrap();
 
-    let vm_image_path_in_host = find_single_file_in_dirs(
-        VM_IMAGE_SEARCH_PATHS.map(|p| original_root_path.join(p)),
-        &FILES_TO_IGNORE.map(|f| original_root_path.join(f)),
-    )?;
+    // mount user-provided VM image files into container
 
-    // mount user-provided VM image file into container
+    let mirror_vm_image_dir_path_in_container = Path::new("/").join(VM_IMAGE_DIR_PATH_IN_CONTAINER);
+    let mirror_vm_image_dir_path_in_host = spec.root_path().join(VM_IMAGE_DIR_PATH_IN_CONTAINER);
 
-    let mirror_vm_image_path_in_container =
-        Path::new("crun-vm/image").join(vm_image_path_in_host.file_name().unwrap());
-    let mirror_vm_image_path_in_host = spec.root_path().join(&mirror_vm_image_path_in_container);
-    let mirror_vm_image_path_in_container = Path::new("/").join(mirror_vm_image_path_in_container);
+    // Only one container at a time may use a --persistent VM image. We check this here, while
+    // holding the lock until we're done setting up the image, and the container entrypoint then
+    // takes the lock again for as long as the container runs.
+    //
+    // Nothing holds the lock between the end of `create` and the start of the entrypoint, so two
+    // containers created with the same image before either is started both succeed here. The
+    // second one to start then fails in its entrypoint before booting the VM, so the image is
+    // never used by two VMs at once, but that failure only shows up when starting.
+    let _lock;
 
     let private_dir = if custom_options.persistent {
-        let vm_image_dir_path = vm_image_path_in_host.parent().unwrap();
         let vm_image_dir_name = vm_image_dir_path.file_name().unwrap();
 
-        let overlay_private_dir_name = format!(".crun-vm.{}.tmp", vm_image_dir_name.as_str());
+        let lock_path = vm_image_dir_path
+            .parent()
+            .unwrap()
+            .join(format!(".crun-vm.{}.lock", vm_image_dir_name.as_str()));
+
+        _lock = try_lock_file(&lock_path)?.ok_or_else(|| {
+            anyhow!(
+                "VM image {} is already in use by another container",
+                vm_image_dir_path.as_str()
+            )
+        })?;
+
+        if let Some(context) = spec.mount_label() {
+            set_file_context(&lock_path, context)?;
+        }
+
+        spec.mounts_push(
+            oci_spec::runtime::MountBuilder::default()
+                .typ("bind")
+                .source(&lock_path)
+                .destination("/crun-vm/image.lock")
+                .options(["bind".to_string(), "rprivate".to_string()])
+                .build()
+                .unwrap(),
+        );
+
+        // overlayfs requires the private dir to be on the same file system as the image
+        let overlay_private_dir_name = format!(
+            ".crun-vm.{}.{}.tmp",
+            vm_image_dir_name.as_str(),
+            container_id
+        );
         let overlay_private_dir_path = vm_image_dir_path
             .parent()
             .unwrap()
             .join(overlay_private_dir_name);
 
+        // let `delete` find the private dir
+        let symlink_path = bundle_path.join(VM_IMAGE_PRIVATE_DIR_NAME);
+        symlink(&overlay_private_dir_path, &symlink_path)?;
+        rollback.push(move || Ok(fs::remove_file(symlink_path)?));
+
         overlay_private_dir_path
     } else {
-        bundle_path.join("crun-vm-vm-image-overlayfs")
+        bundle_path.join(VM_IMAGE_PRIVATE_DIR_NAME)
     };
 
     // We may need to change the VM image context to actually be able to access it, but we don't
-    // want to change the user's original image file and also don't want to do a full data copy, so
-    // we use an overlayfs mount, which allows us to expose the same file with a different context.
-    //
-    // TODO: Clean up `private_dir` when VM is terminated (would be best-effort, but better than
-    // nothing).
+    // want to change the user's original image files and also don't want to do a full data copy,
+    // so we use an overlayfs mount, which allows us to expose the same files with a different
+    // context. This is undone by `delete`.
+    rollback.push({
+        let private_dir = private_dir.clone();
+        move || remove_dir_all_if_exists(private_dir)
+    });
+
     bind_mount_dir_with_different_context(
-        vm_image_path_in_host.parent().unwrap(),
-        mirror_vm_image_path_in_host.parent().unwrap(),
+        vm_image_dir_path,
+        &mirror_vm_image_dir_path_in_host,
         spec.mount_label(),
         custom_options.persistent,
-        private_dir,
+        &private_dir,
+    )?;
+
+    rollback.push({
+        let path = mirror_vm_image_dir_path_in_host.clone();
+        move || unmount_if_mounted(path)
+    });
+
+    let mut vm_image_disks = VmImageDisks::default();
+
+    // Compressed images are decompressed and appliances unpacked into the cache, and an appliance
+    // may have several disks, so first figure out which files the disks are actually backed by.
+    // We look at the files through their paths in the host rather than through the overlayfs
+    // mounts, so that cached digests keep applying across containers.
+
+    let mut vm_image_paths = vec![];
+    let mut cache_locks = vec![];
+
+    for (i, vm_image_path_in_host) in vm_image_paths_in_host.iter().enumerate() {
+        let mut path_in_host = vm_image_path_in_host.clone();
+        let mut path_in_container =
+            mirror_vm_image_dir_path_in_container.join(path_in_host.file_name().unwrap());
+
+        if let Some(decompressor) = get_decompressor(&path_in_host)? {
+            // writes would go to the shared cache, and not back to the original file
+            ensure!(
+                !custom_options.persistent,
+                "--persistent can't be used with compressed VM images"
+            );
+
+            let cache_lock;
+            (path_in_host, path_in_container, cache_lock) = set_up_decompressed_vm_image(
+                spec,
+                &path_in_host,
+                decompressor,
+                i,
+                &private_dir,
+                &host_config.cache_dir,
+                rollback,
+            )?;
+            cache_locks.push(cache_lock);
+        }
+
+        if is_tar_archive(&path_in_host)? {
+            ensure!(
+                !custom_options.persistent,
+                "--persistent can't be used with OVA archives or Vagrant boxes"
+            );
+
+            let appliance = import_appliance(&path_in_host, &host_config.cache_dir)?;
+            let dir_path_in_container = set_up_cache_entry_mount(
+                spec,
+                &appliance.dir_path,
+                &format!("{i}-appliance"),
+                &private_dir,
+                rollback,
+            )?;
+
+            for file_name in &appliance.disk_file_names {
+                vm_image_paths.push((
+                    appliance.dir_path.join(file_name),
+                    dir_path_in_container.join(file_name),
+                ));
+            }
+
+            // the hardware of the appliance we boot from applies to the whole VM
+            if i == 0 {
+                vm_image_disks.hardware = appliance.hardware;
+            }
+
+            cache_locks.push(appliance.lock);
+        } else {
+            vm_image_paths.push((path_in_host, path_in_container));
+        }
+    }
+
+    if !cache_locks.is_empty() {
+        // The container entrypoint keeps holding shared locks on the cache entries that the
+        // container uses, so that they aren't evicted while it runs.
+        for (i, lock) in cache_locks.iter().enumerate() {
+            if let Some(context) = spec.mount_label() {
+                set_file_context(&lock.path, context)?;
+            }
+
+            spec.mounts_push(
+                oci_spec::runtime::MountBuilder::default()
+                    .typ("bind")
+                    .source(&lock.path)
+                    .destination(format!("/crun-vm/cache-locks/{i}"))
+                    .options(["bind".to_string(), "rprivate".to_string()])
+                    .build()
+                    .unwrap(),
+            );
+        }
+
+        // keep the cache from growing indefinitely, while holding locks on our own entries; failing
+        // to do so shouldn't keep the container from starting
+        if let Err(e) = evict_cache_entries(&host_config.cache_dir, host_config.cache_max_size.0) {
+            eprintln!("warning: failed to evict cache entries: {e:#}");
+        }
+    }
+
+    if let Some(image_key) = &custom_options.image_key {
+        ensure!(
+            image_key.is_absolute(),
+            "--image-key must be an absolute path in the container"
+        );
+    }
+
+    ensure!(
+        !(custom_options.encrypt_overlay && custom_options.persistent),
+        "--encrypt-overlay doesn't apply when using --persistent, since there is no overlay"
+    );
+
+    // Like with --persistent, only one container at a time may use a given --overlay-dir, and the
+    // container entrypoint holds the lock for as long as the container runs. The same window
+    // between `create` and the entrypoint applies.
+    let _overlay_dir_lock;
+
+    if let Some(overlay_dir) = &custom_options.overlay_dir {
+        ensure!(
+            !custom_options.persistent,
+            "--overlay-dir doesn't apply when using --persistent, since there is no overlay"
+        );
+        ensure!(
+            !custom_options.encrypt_overlay,
+            "--overlay-dir can't be used with --encrypt-overlay, whose overlays don't outlive \
+            the container"
+        );
+
+        fs::create_dir_all(overlay_dir)?;
+
+        let lock_path = overlay_dir.join(".crun-vm.lock");
+
+        _overlay_dir_lock = try_lock_file(&lock_path)?.ok_or_else(|| {
+            anyhow!(
+                "--overlay-dir {} is already in use by another container",
+                overlay_dir.as_str()
+            )
+        })?;
+
+        if let Some(context) = spec.mount_label() {
+            set_file_context(&lock_path, context)?;
+        }
+
+        spec.mounts_push(
+            oci_spec::runtime::MountBuilder::default()
+                .typ("bind")
+                .source(lock_path.canonicalize()?)
+                .destination("/crun-vm/image.lock")
+                .options(["bind".to_string(), "rprivate".to_string()])
+                .build()
+                .unwrap(),
+        );
+    }
+
+    // encrypted overlays are created by the container entrypoint, since their key must only ever
+    // exist in the container's memory
+    let mut encrypted_overlays_script = String::new();
+    let mut uses_image_key = false;
+
+    // --persistent VM images are grown by the container entrypoint, so that the user's image is
+    // only modified once the container was successfully created and holds the image's lock
+    let mut resize_script = String::new();
+
+    let mut overlays = VmImageOverlays::default();
+
+    for (i, (vm_image_path_in_host, vm_image_path_in_container)) in
+        vm_image_paths.into_iter().enumerate()
+    {
+        let mut vm_image_info = VmImageInfo::of(&vm_image_path_in_host)?;
+
+        ensure!(
+            vm_image_info.external_files.is_empty(),
+            "VM image {} references external data files, which is not supported",
+            vm_image_path_in_host.file_name().unwrap().to_string_lossy()
+        );
+
+        if vm_image_info.encrypted {
+            ensure!(
+                custom_options.image_key.is_some(),
+                "VM image {} is encrypted; pass a file with its passphrase using --image-key",
+                vm_image_path_in_host.file_name().unwrap().to_string_lossy()
+            );
+            uses_image_key = true;
+        }
+
+        if is_iso_image(&vm_image_path_in_host)? {
+            // ISO images are attached read-only, so they need neither an overlay nor locking
+            vm_image_info.path = vm_image_path_in_container;
+            vm_image_disks.cdroms.push(vm_image_info);
+            vm_image_disks.boot_from_cdrom |= i == 0;
+            continue;
+        }
+
+        // --disk-size only applies to the boot disk
+        let disk_size = match custom_options.disk_size {
+            Some(size) if i == 0 => size,
+            _ => vm_image_info.size,
+        };
+        ensure!(
+            disk_size >= vm_image_info.size,
+            "--disk-size must not be smaller than the VM image's virtual size ({} bytes)",
+            vm_image_info.size
+        );
+
+        if custom_options.persistent {
+            // We want to propagate writes but not removal, so that the user's file isn't deleted
+            // by Podman on cleanup, so we bind mount it on top of itself.
+
+            let mirror_vm_image_path_in_host = spec
+                .root_path()
+                .join(vm_image_path_in_container.strip_prefix("/")?);
+
+            bind_mount_file(&mirror_vm_image_path_in_host, &mirror_vm_image_path_in_host)?;
+
+            rollback.push({
+                let path = mirror_vm_image_path_in_host.clone();
+                move || unmount_if_mounted(path)
+            });
+
+            if disk_size > vm_image_info.size {
+                ensure!(
+                    !vm_image_info.encrypted,
+                    "--disk-size can't grow an encrypted --persistent VM image"
+                );
+
+                // this permanently modifies the user's image, so make sure they really want it
+                ensure!(
+                    custom_options.confirm_resize,
+                    "--disk-size would grow the --persistent VM image in place; pass \
+                    --confirm-resize to proceed"
+                );
+
+                resize_script.push_str(&format!(
+                    "qemu-img resize -q -f {} {} {disk_size}\n",
+                    shell_quote(&vm_image_info.format),
+                    shell_quote(vm_image_path_in_container.as_str()),
+                ));
+                vm_image_info.size = disk_size;
+            }
+
+            vm_image_info.path = vm_image_path_in_container;
+
+            let secret = vm_image_info.encrypted.then_some(VM_IMAGE_SECRET_UUID);
+            vm_image_disks.disks.push(VmImageDisk {
+                info: vm_image_info,
+                secret,
+                encrypted_backing: None,
+            });
+        } else {
+            // The overlayfs mount already isolates the user's original image files from writes,
+            // but to ensure that we get copy-on-write and page cache sharing even when the
+            // underlying file system doesn't support reflinks, we create a qcow2 overlay for each
+            // and use that as the image.
+
+            let overlay_file_name = if i == 0 {
+                "image-overlay.qcow2".to_string()
+            } else {
+                format!("image-overlay-{i}.qcow2")
+            };
+            let mut overlay_vm_image_path_in_host =
+                spec.root_path().join("crun-vm").join(&overlay_file_name);
+            let overlay_vm_image_path_in_container = Path::new("/crun-vm").join(&overlay_file_name);
+
+            vm_image_info.path = vm_image_path_in_container;
+            let mut overlay_size = disk_size;
+
+            if custom_options.encrypt_overlay {
+                encrypted_overlays_script.push_str(&format!(
+                    "qemu-img create -q -f qcow2 --object secret,id=key,file=\"$1\" \
+                    -o encrypt.format=luks,encrypt.key-secret=key -u -F {} -b {} {} {}\n",
+                    shell_quote(&vm_image_info.format),
+                    shell_quote(vm_image_info.path.as_str()),
+                    shell_quote(overlay_vm_image_path_in_container.as_str()),
+                    disk_size,
+                ));
+            } else if let Some(overlay_dir) = &custom_options.overlay_dir {
+                overlay_vm_image_path_in_host =
+                    overlay_dir.canonicalize()?.join(&overlay_file_name);
+                overlay_size = set_up_persisted_overlay(
+                    spec,
+                    &overlay_vm_image_path_in_host,
+                    &vm_image_info,
+                    &get_file_digest(&vm_image_path_in_host, &host_config.cache_dir)?,
+                    disk_size,
+                    &overlay_vm_image_path_in_container,
+                    rollback,
+                )?;
+            } else {
+                create_overlay_vm_image(&overlay_vm_image_path_in_host, &vm_image_info, disk_size)?;
+            }
+
+            overlays.overlays.push(VmImageOverlay {
+                path: overlay_vm_image_path_in_host,
+                base_path: vm_image_path_in_host,
+                base_format: vm_image_info.format.clone(),
+                encrypted: vm_image_info.encrypted || custom_options.encrypt_overlay,
+            });
+
+            // libvirt can't probe the backing file of an overlay if it is encrypted
+            let encrypted_backing = vm_image_info
+                .encrypted
+                .then(|| (vm_image_info.clone(), VM_IMAGE_SECRET_UUID));
+
+            vm_image_disks.disks.push(VmImageDisk {
+                info: VmImageInfo {
+                    path: overlay_vm_image_path_in_container,
+                    size: overlay_size,
+                    format: "qcow2".to_string(),
+                    backing_file: Some(vm_image_info.path),
+                    external_files: vec![],
+                    encrypted: custom_options.encrypt_overlay,
+                },
+                secret: custom_options
+                    .encrypt_overlay
+                    .then_some(OVERLAY_SECRET_UUID),
+                encrypted_backing,
+            });
+        }
+    }
+
+    if !overlays.overlays.is_empty() {
+        // The container entrypoint holds a lock on this file while the container runs, so that
+        // `commit` can tell whether the overlays are in use.
+        overlays.lock_path = match &custom_options.overlay_dir {
+            Some(overlay_dir) => overlay_dir.join(".crun-vm.lock").canonicalize()?,
+            None => {
+                let lock_path = spec.root_path().join("crun-vm/image.lock");
+                fs::write(&lock_path, "")?;
+                lock_path
+            }
+        };
+
+        let overlays_file_path = spec
+            .root_path()
+            .join(VM_IMAGE_OVERLAYS_FILE_PATH_IN_CONTAINER);
+        fs::write(overlays_file_path, serde_json::to_vec(&overlays)?)?;
+    }
+
+    ensure!(
+        uses_image_key || custom_options.image_key.is_none(),
+        "--image-key was given but none of the VM image files is encrypted"
+    );
+
+    // the entrypoint defines a libvirt secret for each of these, with the passphrase read from the
+    // given file in the container
+    let mut secrets = String::new();
+    if let Some(image_key) = &custom_options.image_key {
+        secrets.push_str(&format!("{VM_IMAGE_SECRET_UUID} {}\n", image_key.as_str()));
+    }
+    if !encrypted_overlays_script.is_empty() {
+        secrets.push_str(&format!(
+            "{OVERLAY_SECRET_UUID} {KEYS_DIR_PATH_IN_CONTAINER}/overlay.key\n"
+        ));
+        fs::write(
+            spec.root_path().join("crun-vm/encrypted-overlays.sh"),
+            encrypted_overlays_script,
+        )?;
+    }
+    if !secrets.is_empty() {
+        fs::write(spec.root_path().join("crun-vm/secrets"), secrets)?;
+    }
+    if !resize_script.is_empty() {
+        fs::write(
+            spec.root_path().join("crun-vm/resize-image.sh"),
+            resize_script,
+        )?;
+    }
+
+    if vm_image_disks.boot_from_cdrom {
+        ensure!(
+            custom_options.disk_size.is_none(),
+            "--disk-size doesn't apply when booting an installer ISO image, use --install-disk \
+            size=<size> instead"
+        );
+
+        ensure!(
+            !custom_options.encrypt_overlay,
+            "--encrypt-overlay doesn't apply when booting an installer ISO image"
+        );
+        ensure!(
+            custom_options.overlay_dir.is_none(),
+            "--overlay-dir doesn't apply when booting an installer ISO image, use --install-disk \
+            persist=<path> instead"
+        );
+
+        let install_disk = custom_options.install_disk.clone().unwrap_or_default();
+        let install_disk_info = set_up_install_disk(spec, &install_disk, rollback)?;
+
+        // the installer is booted first and installs to this disk
+        vm_image_disks.disks.insert(
+            0,
+            VmImageDisk {
+                info: install_disk_info,
+                secret: None,
+                encrypted_backing: None,
+            },
+        );
+    } else {
+        ensure!(
+            custom_options.install_disk.is_none(),
+            "--install-disk requires the boot disk to be an installer ISO image"
+        );
+    }
+
+    Ok(vm_image_disks)
+}
+
+/// Decompresses a VM image into the cache, unless that was already done, and exposes the result in
+/// the container.
+///
+/// Returns the paths of the decompressed image in the cache and in the container, and a lock on the
+/// cache entry.
+fn set_up_decompressed_vm_image(
+    spec: &oci_spec::runtime::Spec,
+    vm_image_path: &Path,
+    decompressor: &str,
+    index: usize,
+    private_dir: &Path,
+    cache_dir: &Path,
+    rollback: &mut Rollback,
+) -> Result<(PathBuf, PathBuf, CacheEntryLock)> {
+    // Entries are keyed by the digest of the compressed file, so that identical images are only
+    // decompressed once, regardless of their names or which container image they come from.
+    let digest = get_file_digest(vm_image_path, cache_dir)?;
+    let entry_path = cache_dir.join("decompressed").join(&digest);
+    let lock = lock_cache_entry(&entry_path)?;
+
+    if !entry_path.is_dir() {
+        let temp_entry_path = entry_path.with_extension(format!("tmp-{}", process::id()));
+        fs::create_dir_all(&temp_entry_path)?;
+
+        let result = decompress_file(vm_image_path, &temp_entry_path.join("image"), decompressor)
+            .and_then(|()| {
+                // another container may have concurrently decompressed the same image, in which
+                // case we just use that one
+                match fs::rename(&temp_entry_path, &entry_path) {
+                    Ok(()) => Ok(()),
+                    Err(_) if entry_path.is_dir() => remove_dir_all_if_exists(&temp_entry_path),
+                    Err(e) => Err(e.into()),
+                }
+            });
+
+        if result.is_err() {
+            remove_dir_all_if_exists(&temp_entry_path)?;
+        }
+
+        result.with_context(|| format!("failed to decompress {}", vm_image_path.as_str()))?;
+    }
+
+    let dir_path_in_container =
+        set_up_cache_entry_mount(spec, &entry_path, &index.to_string(), private_dir, rollback)?;
+
+    Ok((
+        entry_path.join("image"),
+        dir_path_in_container.join("image"),
+        lock,
+    ))
+}
+
+/// Exposes a directory in the cache in the container, under `VM_IMAGE_CACHE_DIR_PATH_IN_CONTAINER`
+/// with the given name.
+///
+/// Returns the path of the directory in the container.
+fn set_up_cache_entry_mount(
+    spec: &oci_spec::runtime::Spec,
+    entry_path: &Path,
+    name: &str,
+    private_dir: &Path,
+    rollback: &mut Rollback,
+) -> Result<PathBuf> {
+    // As with the original image, we use an overlayfs mount to expose the cache entry with the
+    // container's SELinux context. This is undone by `delete`.
+
+    let dir_path_in_container = Path::new(VM_IMAGE_CACHE_DIR_PATH_IN_CONTAINER).join(name);
+    let dir_path_in_host = spec.root_path().join(&dir_path_in_container);
+
+    bind_mount_dir_with_different_context(
+        entry_path,
+        &dir_path_in_host,
+        spec.mount_label(),
+        false,
+        private_dir.join(format!("cache-{name}")),
     )?;
 
-    let mut vm_image_info = VmImageInfo::of(&mirror_vm_image_path_in_host)?;
+    rollback.push({
+        let path = dir_path_in_host.clone();
+        move || unmount_if_mounted(path)
+    });
 
-    if custom_options.persistent {
-        // We want to propagate writes but not removal, so that the user's file isn't deleted by
-        // Podman on cleanup, so we bind mount it on top of itself.
+    Ok(Path::new("/").join(dir_path_in_container))
+}
+
+/// The disks attached to the VM for the VM image files.
+#[derive(Default)]
+struct VmImageDisks {
+    /// Disks in the order they are attached, the first one being the boot disk unless
+    /// `boot_from_cdrom` is set.
+    disks: Vec<VmImageDisk>,
+    /// ISO images, which are attached as read-only CD-ROMs.
+    cdroms: Vec<VmImageInfo>,
+    /// Whether to boot from the first CD-ROM, *i.e.*, run an installer, before the first disk.
+    boot_from_cdrom: bool,
+    /// Virtual hardware declared by the appliance we boot from, if any.
+    hardware: ApplianceHardware,
+}
+
+/// What `commit` needs to know about the qcow2 overlays that hold the VM's changes to its image.
+#[derive(Default, Serialize, Deserialize)]
+pub struct VmImageOverlays {
+    /// File that the container entrypoint holds a lock on for as long as the container runs.
+    pub lock_path: PathBuf,
+    pub overlays: Vec<VmImageOverlay>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VmImageOverlay {
+    /// Path of the overlay in the host.
+    pub path: PathBuf,
+    /// Path in the host of the VM image file that the overlay is on top of.
+    pub base_path: PathBuf,
+    pub base_format: String,
+    /// Whether the overlay or the VM image file is encrypted.
+    pub encrypted: bool,
+}
+
+/// A disk attached to the VM, which is either a VM image file itself or a qcow2 overlay on top of
+/// it.
+struct VmImageDisk {
+    info: VmImageInfo,
+    /// UUID of the libvirt secret with the passphrase of the disk, if it is encrypted.
+    secret: Option<&'static str>,
+    /// The VM image that the overlay is on top of and the UUID of the libvirt secret with its
+    /// passphrase, if it is encrypted.
+    encrypted_backing: Option<(VmImageInfo, &'static str)>,
+}
+
+/// Sets up a qcow2 overlay on top of a VM image in a file on the host, reusing it if it already
+/// exists, and mounts it into the container.
+///
+/// The digest of the VM image is recorded alongside the overlay, so that we notice if the overlay
+/// is later used with a different VM image, which would silently corrupt the VM's disk.
+///
+/// Returns the overlay's virtual size.
+fn set_up_persisted_overlay(
+    spec: &mut oci_spec::runtime::Spec,
+    overlay_path: &Path,
+    vm_image_info: &VmImageInfo,
+    base_digest: &str,
+    size: u64,
+    overlay_path_in_container: &Path,
+    rollback: &mut Rollback,
+) -> Result<u64> {
+    let base_digest_path = PathBuf::from(format!("{}.base-sha256", overlay_path.as_str()));
+
+    let size = if overlay_path.try_exists()? {
+        let recorded_digest = fs::read_to_string(&base_digest_path).with_context(|| {
+            format!(
+                "overlay {} exists but wasn't created by crun-vm",
+                overlay_path.as_str()
+            )
+        })?;
+
+        ensure!(
+            recorded_digest.trim() == base_digest,
+            "overlay {} was created on top of a different VM image than {}; remove it to start \
+            over from the current image",
+            overlay_path.as_str(),
+            vm_image_info.path.file_name().unwrap().to_string_lossy()
+        );
 
-        bind_mount_file(&mirror_vm_image_path_in_host, &mirror_vm_image_path_in_host)?;
+        // The overlay's backing file is a path in the container, so we can't resize it from
+        // here, as qemu-img would need to open the backing file.
+        let overlay_size = VmImageInfo::of(overlay_path)?.size;
+        ensure!(
+            size <= overlay_size,
+            "--disk-size is larger than the existing overlay {} ({} bytes), which can't be \
+            grown; remove it to start over from the current image",
+            overlay_path.as_str(),
+            overlay_size
+        );
 
-        vm_image_info.path = mirror_vm_image_path_in_container;
+        overlay_size
     } else {
-        // The overlayfs mount already isolates the user's original image file from writes, but to
-        // ensure that we get copy-on-write and page cache sharing even when the underlying file
-        // system doesn't support reflinks, we create a qcow2 overlay and use that as the image.
+        create_overlay_vm_image(overlay_path, vm_image_info, size)?;
+        fs::write(&base_digest_path, base_digest)?;
+
+        rollback.push({
+            let overlay_path = overlay_path.to_path_buf();
+            move || {
+                fs::remove_file(overlay_path)?;
+                Ok(fs::remove_file(base_digest_path)?)
+            }
+        });
+
+        size
+    };
+
+    spec.mounts_push(
+        oci_spec::runtime::MountBuilder::default()
+            .typ("bind")
+            .source(overlay_path.canonicalize()?)
+            .destination(overlay_path_in_container)
+            .options(["bind".to_string(), "rprivate".to_string()])
+            .build()
+            .unwrap(),
+    );
 
-        let overlay_vm_image_path_in_container = PathBuf::from("crun-vm/image-overlay.qcow2");
-        let overlay_vm_image_path_in_host =
-            spec.root_path().join(&overlay_vm_image_path_in_container);
-        let overlay_vm_image_path_in_container =
-            Path::new("/").join(overlay_vm_image_path_in_container);
+    Ok(size)
+}
+
+/// Creates the blank disk that an installer ISO image installs to.
+fn set_up_install_disk(
+    spec: &mut oci_spec::runtime::Spec,
+    install_disk: &InstallDisk,
+    rollback: &mut Rollback,
+) -> Result<VmImageInfo> {
+    let path_in_container = PathBuf::from("crun-vm/install-disk");
+
+    match &install_disk.persist {
+        Some(persist_path) => set_up_persisted_disk(
+            spec,
+            "install disk",
+            persist_path,
+            &install_disk.format,
+            install_disk.size,
+            &path_in_container,
+            rollback,
+        )?,
+        None => {
+            // the container root is in the bundle, so the disk goes away with the container
+            create_empty_vm_image(
+                &spec.root_path().join(&path_in_container),
+                &install_disk.format,
+                install_disk.size,
+            )?;
+        }
+    }
+
+    Ok(VmImageInfo {
+        path: Path::new("/").join(path_in_container),
+        size: install_disk.size,
+        format: install_disk.format.clone(),
+        backing_file: None,
+        external_files: vec![],
+        encrypted: false,
+    })
+}
+
+/// Returns the paths of the VM image files in the container image, with the boot disk first and
+/// the remaining ones in order of file name, after checking their digests.
+fn find_vm_image_files(
+    original_root_path: &Path,
+    custom_options: &CustomOptions,
+    cache_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    // docker may add these files to the root of the container
+    const FILES_TO_IGNORE: [&str; 2] = [".dockerinit", ".dockerenv"];
+
+    let files_to_ignore: Vec<PathBuf> = FILES_TO_IGNORE
+        .map(|f| original_root_path.join(f))
+        .into_iter()
+        .chain(VM_IMAGE_SEARCH_PATHS.iter().flat_map(|dir| {
+            VM_IMAGE_CONFIG_FILE_NAMES.map(|f| original_root_path.join(dir).join(f))
+        }))
+        .collect();
+
+    let mut paths: Vec<PathBuf> = find_files_in_dirs(
+        VM_IMAGE_SEARCH_PATHS.map(|p| original_root_path.join(p)),
+        &files_to_ignore,
+    )?
+    .into_iter()
+    .filter(|p| !is_vm_image_metadata_file(p))
+    .collect();
+
+    ensure!(!paths.is_empty(), "no VM image files found");
+
+    let dir_path = paths[0].parent().unwrap().to_path_buf();
+    ensure!(
+        paths.iter().all(|p| p.parent() == Some(&dir_path)),
+        "VM image files must all be in the same directory, either / or /disk/"
+    );
+
+    // Check the digests given for specific files before looking inside any of them. Digests given
+    // for the boot disk are checked once we know which file that is.
+    for path in &paths {
+        verify_vm_image(path, false, original_root_path, custom_options, cache_dir)?;
+    }
+
+    // The original VM image under an overlay saved by `crun-vm commit --rebase` is part of that
+    // disk and not a disk of its own. Such overlays are qcow2 files that refer to their backing
+    // file by name, so only those backing files are recognized.
+    let mut backing_paths = vec![];
+    for path in &paths {
+        if let Some(backing_file) = get_qcow2_backing_file(path)? {
+            if is_file_name(&backing_file) && dir_path.join(&backing_file) != *path {
+                backing_paths.push(dir_path.join(backing_file));
+            }
+        }
+    }
+    paths.retain(|p| !backing_paths.contains(p));
 
-        vm_image_info.path = mirror_vm_image_path_in_container;
-        create_overlay_vm_image(&overlay_vm_image_path_in_host, &vm_image_info)?;
+    if let Some(boot_disk) = &custom_options.boot_disk {
+        let boot_disk_path = resolve_vm_image_path(boot_disk, original_root_path, &dir_path);
 
-        vm_image_info.path = overlay_vm_image_path_in_container;
+        let i = paths
+            .iter()
+            .position(|p| *p == boot_disk_path)
+            .ok_or_else(|| {
+                anyhow!(
+                    "--boot-disk {} is not one of the VM image files",
+                    boot_disk.as_str()
+                )
+            })?;
+
+        let path = paths.remove(i);
+        paths.insert(0, path);
     }
 
-    Ok(vm_image_info)
+    verify_vm_image(
+        &paths[0],
+        true,
+        original_root_path,
+        custom_options,
+        cache_dir,
+    )?;
+
+    Ok(paths)
+}
+
+/// Whether the given path is a single file name, with no directory components.
+fn is_file_name(path: impl AsRef<Path>) -> bool {
+    let mut components = path.as_ref().components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+/// Resolves a path given in options that refers to a VM image file, which is either absolute and
+/// relative to the root of the container image, or relative to the directory containing the VM
+/// image files.
+fn resolve_vm_image_path(path: &Path, original_root_path: &Path, dir_path: &Path) -> PathBuf {
+    match path.strip_prefix("/") {
+        Ok(relative) => original_root_path.join(relative),
+        Err(_) => dir_path.join(path),
+    }
+}
+
+/// Checks that a VM image file has the SHA-256 digest given for it in `--image-digest` or in a
+/// `<file>.sha256` or `<file>.sha256sum` file next to it, if any.
+fn verify_vm_image(
+    path: &Path,
+    is_boot_disk: bool,
+    original_root_path: &Path,
+    custom_options: &CustomOptions,
+    cache_dir: &Path,
+) -> Result<()> {
+    let dir_path = path.parent().unwrap();
+    let file_name = path.file_name().unwrap().to_string_lossy();
+
+    let mut expected_digests = vec![];
+
+    for image_digest in &custom_options.image_digest {
+        let applies = match &image_digest.file {
+            Some(file) => resolve_vm_image_path(file, original_root_path, dir_path) == path,
+            None => is_boot_disk,
+        };
+
+        if applies {
+            expected_digests.push(image_digest.sha256.clone());
+        }
+    }
+
+    for extension in ["sha256", "sha256sum"] {
+        let sidecar_path = dir_path.join(format!("{file_name}.{extension}"));
+
+        if sidecar_path.is_file() {
+            // either just the digest, or the output of sha256sum
+            let contents = fs::read_to_string(&sidecar_path)?;
+            let hex = contents.split_whitespace().next().unwrap_or_default();
+
+            let digest = parse_sha256_digest(hex)
+                .with_context(|| format!("invalid digest file {}", sidecar_path.as_str()))?;
+
+            expected_digests.push(digest);
+        }
+    }
+
+    if expected_digests.is_empty() {
+        return Ok(());
+    }
+
+    let digest = get_file_digest(path, cache_dir)?;
+
+    for expected_digest in expected_digests {
+        ensure!(
+            digest == expected_digest,
+            "VM image {file_name} has SHA-256 digest {digest}, but {expected_digest} was expected; \
+            refusing to boot it"
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether the given file is a README or checksum file, which may be distributed alongside VM
+/// image files but aren't disks themselves.
+fn is_vm_image_metadata_file(path: &Path) -> bool {
+    const CHECKSUM_EXTENSIONS: [&str; 8] = [
+        "md5",
+        "md5sum",
+        "sha1",
+        "sha1sum",
+        "sha256",
+        "sha256sum",
+        "sha512",
+        "sha512sum",
+    ];
+
+    let name = path.file_name().unwrap().to_string_lossy().to_lowercase();
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+
+    name.starts_with("readme")
+        || name.starts_with("checksum")
+        || name.ends_with("sums") // e.g., SHA256SUMS
+        || extension.is_some_and(|e| CHECKSUM_EXTENSIONS.contains(&e.as_str()))
 }
 
 #[derive(Default)]
 struct Mounts {
+    /// The kind of device used for `virtiofs` mounts and the `FILE_SHARES`.
+    fs_driver: FsDriver,
     virtiofs: Vec<VirtiofsMount>,
     tmpfs: Vec<TmpfsMount>,
     block_device: Vec<BlockDeviceMount>,
+    file: Vec<FileMount>,
+}
+
+/// Returns the driver to expose directories with, which is the one given with --fs-driver, if any,
+/// or else virtiofs if virtiofsd is installed and 9p otherwise. The machine type isn't considered,
+/// as it may be changed by libvirt XML overlays, which are only merged later.
+fn get_fs_driver(custom_options: &CustomOptions, host_config: &HostConfig) -> FsDriver {
+    custom_options.fs_driver.unwrap_or_else(|| {
+        if host_config.virtiofsd.exists() {
+            FsDriver::Virtiofs
+        } else {
+            FsDriver::NineP
+        }
+    })
+}
+
+/// Regular files are exposed to the guest through one of these shares of a directory, depending on
+/// whether they are mounted read-only, and then bind mounted at their destination by the guest.
+/// QEMU itself enforces that the read-only share is read-only, so the guest can't get around it by
+/// remounting.
+const FILE_SHARES: [FileShare; 2] = [
+    FileShare {
+        path_in_container: "/crun-vm/mounts/files",
+        path_in_guest: "/run/crun-vm/files",
+        tag: "crun-vm-files",
+        readonly: false,
+    },
+    FileShare {
+        path_in_container: "/crun-vm/mounts/files-ro",
+        path_in_guest: "/run/crun-vm/files-ro",
+        tag: "crun-vm-files-ro",
+        readonly: true,
+    },
+];
+
+struct FileShare {
+    path_in_container: &'static str,
+    path_in_guest: &'static str,
+    tag: &'static str,
+    readonly: bool,
+}
+
+impl Mounts {
+    /// Returns the `FILE_SHARES` that any of the file mounts go through.
+    fn file_shares(&self) -> impl Iterator<Item = &'static FileShare> + '_ {
+        FILE_SHARES
+            .iter()
+            .filter(|share| self.file.iter().any(|m| m.readonly == share.readonly))
+    }
 }
 
 struct BlockDeviceMount {
@@ -201,36 +1209,82 @@ struct BlockDeviceMount {
     is_regular_file: bool,
     path_in_container: PathBuf,
     path_in_guest: PathBuf,
-    readonly: bool,
+    options: DiskOptions,
+}
+
+impl BlockDeviceMount {
+    /// Returns the serial number of the disk, given its index in `Mounts::block_device`.
+    fn serial(&self, index: usize) -> String {
+        self.options
+            .serial
+            .clone()
+            .unwrap_or_else(|| format!("crun-vm-block-{index}"))
+    }
+
+    /// Returns the path under which the guest's udev exposes the disk, given its index in
+    /// `Mounts::block_device`.
+    fn path_in_guest_by_id(&self, index: usize) -> PathBuf {
+        let prefix = match self.options.bus {
+            DiskBus::Virtio => "virtio-",
+            DiskBus::Scsi => "scsi-0QEMU_QEMU_HARDDISK_",
+            DiskBus::Nvme => "nvme-QEMU_NVMe_Ctrl_",
+            DiskBus::Sata => "ata-QEMU_HARDDISK_",
+        };
+
+        PathBuf::from(format!("/dev/disk/by-id/{prefix}{}", self.serial(index)))
+    }
 }
 
 struct VirtiofsMount {
     path_in_container: PathBuf,
     path_in_guest: PathBuf,
+    readonly: bool,
+    /// Path to the virtiofsd binary or wrapper script to use, in the container.
+    virtiofsd: PathBuf,
+    options: VirtiofsOptions,
 }
 
 struct TmpfsMount {
     path_in_guest: PathBuf,
 }
 
-fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Result<()> {
+struct FileMount {
+    /// Path to the file in the guest, under the `FileShare::path_in_guest` of its share.
+    path_in_share: PathBuf,
+    path_in_guest: PathBuf,
+    readonly: bool,
+}
+
+fn set_up_mounts(
+    spec: &mut oci_spec::runtime::Spec,
+    mounts: &mut Mounts,
+    custom_options: &CustomOptions,
+) -> Result<()> {
     const TARGETS_TO_IGNORE: &[&str] = &[
         "/etc/hostname",
         "/etc/hosts",
         "/etc/resolv.conf",
         "/proc",
         "/run/.containerenv",
-        "/run/secrets",
         "/sys",
         "/sys/fs/cgroup",
     ];
 
+    // Secrets are for the container, *e.g.*, the --image-key passphrase, which must not be exposed
+    // to the VM.
+    const TARGET_DIRS_TO_IGNORE: &[&str] = &["/crun-vm", "/run/secrets"];
+
     let mut new_oci_mounts: Vec<oci_spec::runtime::Mount> = vec![];
 
     for oci_mount in spec.mounts().iter().flatten() {
+        // keep mounts that the container engine sets up for the container itself, and our own
+        // mounts under /crun-vm, in the container instead of exposing them to the VM
         if TARGETS_TO_IGNORE
             .iter()
             .any(|path| oci_mount.destination() == Path::new(path))
+            || TARGET_DIRS_TO_IGNORE
+                .iter()
+                .any(|path| oci_mount.destination().starts_with(path))
         {
             new_oci_mounts.push(oci_mount.clone());
             continue;
@@ -240,6 +1294,12 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
             Some("bind") => {
                 let meta = oci_mount.source().as_ref().unwrap().metadata()?;
 
+                let readonly = oci_mount
+                    .options()
+                    .iter()
+                    .flatten()
+                    .any(|o| o == "ro" || o == "readonly");
+
                 let path_in_container;
 
                 if meta.file_type().is_dir() {
@@ -254,29 +1314,68 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
                     ));
                     let path_in_guest = oci_mount.destination().clone();
 
+                    let options = custom_options
+                        .virtiofs_opt
+                        .iter()
+                        .find(|opt| opt.target == path_in_guest)
+                        .map(|opt| opt.options.clone())
+                        .unwrap_or_default();
+
+                    let virtiofsd =
+                        set_up_virtiofsd_wrapper(spec, mounts.virtiofs.len(), &options)?;
+
                     mounts.virtiofs.push(VirtiofsMount {
                         path_in_container: path_in_container.clone(),
                         path_in_guest,
+                        readonly,
+                        virtiofsd,
+                        options,
                     });
-                } else if meta.file_type().is_block_device() || meta.file_type().is_file() {
-                    let readonly = oci_mount
-                        .options()
-                        .iter()
-                        .flatten()
-                        .any(|o| o == "ro" || o == "readonly");
+                } else if meta.file_type().is_file() && !oci_mount.destination().starts_with("/dev")
+                {
+                    let file_name = mounts.file.len().to_string();
+                    let share = FILE_SHARES.iter().find(|s| s.readonly == readonly).unwrap();
+
+                    path_in_container = Path::new(share.path_in_container).join(&file_name);
+                    let path_in_guest = oci_mount.destination().clone();
 
+                    mounts.file.push(FileMount {
+                        path_in_share: Path::new(share.path_in_guest).join(&file_name),
+                        path_in_guest,
+                        readonly,
+                    });
+                } else if meta.file_type().is_block_device() || meta.file_type().is_file() {
                     path_in_container = PathBuf::from(format!(
                         "crun-vm/mounts/block/{}",
                         mounts.block_device.len()
                     ));
                     let path_in_guest = oci_mount.destination().clone();
 
+                    let format_override = custom_options
+                        .disk_format
+                        .iter()
+                        .find(|f| f.target == path_in_guest)
+                        .map(|f| f.format.as_str());
+
+                    let format = if meta.file_type().is_file() {
+                        get_disk_image_file_format(
+                            oci_mount.source().as_ref().unwrap(),
+                            format_override,
+                            readonly,
+                        )?
+                    } else {
+                        format_override.unwrap_or("raw").to_string()
+                    };
+
                     mounts.block_device.push(BlockDeviceMount {
-                        format: "raw".to_string(),
+                        format,
                         is_regular_file: meta.file_type().is_file(),
                         path_in_container: path_in_container.clone(),
                         path_in_guest,
-                        readonly,
+                        options: DiskOptions {
+                            readonly,
+                            ..Default::default()
+                        },
                     });
                 } else {
                     bail!("can only bind mount regular files, directories, and block devices");
@@ -304,11 +1403,120 @@ fn set_up_mounts(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Res
         }
     }
 
+    for format in &custom_options.disk_format {
+        ensure!(
+            mounts
+                .block_device
+                .iter()
+                .any(|m| m.path_in_guest == format.target),
+            "--disk-format target {} is not a bind-mounted disk",
+            format.target.display()
+        );
+    }
+
+    ensure!(
+        custom_options.virtiofs_opt.is_empty() || mounts.fs_driver == FsDriver::Virtiofs,
+        "--virtiofs-opt requires the virtiofs filesystem driver"
+    );
+
+    for (i, opt) in custom_options.virtiofs_opt.iter().enumerate() {
+        ensure!(
+            mounts
+                .virtiofs
+                .iter()
+                .any(|m| m.path_in_guest == opt.target),
+            "--virtiofs-opt target {} is not a directory mount",
+            opt.target.display()
+        );
+        ensure!(
+            custom_options.virtiofs_opt[..i]
+                .iter()
+                .all(|o| o.target != opt.target),
+            "--virtiofs-opt target {} given more than once",
+            opt.target.display()
+        );
+    }
+
     spec.set_mounts(Some(new_oci_mounts));
 
     Ok(())
 }
 
+/// Returns the format of the given disk image file, or `format_override` if given.
+///
+/// The format of read-only files is detected. Writable files must be raw unless `format_override`
+/// is given: a guest could write an image header into a raw file, which would then be detected as
+/// a different format the next time around, so we refuse to guess rather than use what we detect.
+///
+/// Images with a backing file, an external data file, or extents in other files are refused, since
+/// those files aren't visible to the VM, and a guest could point those references at arbitrary
+/// host files. Files explicitly given as raw aren't probed, as their contents are never
+/// interpreted.
+fn get_disk_image_file_format(
+    path: &Path,
+    format_override: Option<&str>,
+    readonly: bool,
+) -> Result<String> {
+    if format_override == Some("raw") {
+        return Ok("raw".to_string());
+    }
+
+    let info = VmImageInfo::of(path)?;
+
+    ensure!(
+        info.backing_file.is_none(),
+        "disk image {} has a backing file, which is not supported",
+        path.as_str()
+    );
+    ensure!(
+        info.external_files.is_empty(),
+        "disk image {} references external data files, which is not supported",
+        path.as_str()
+    );
+
+    match format_override {
+        Some(format) => Ok(format.to_string()),
+        None if readonly || info.format == "raw" => Ok(info.format),
+        None => bail!(
+            "writable disk image {} looks like a {} image, but its format must be given \
+            explicitly, e.g., with --disk-format target=<path>,format={}, or format=raw if it \
+            really is raw",
+            path.as_str(),
+            info.format,
+            info.format
+        ),
+    }
+}
+
+/// Returns the path in the container of the virtiofsd binary to use for the virtiofs mount with
+/// the given index, creating a wrapper script that passes it additional arguments if necessary.
+fn set_up_virtiofsd_wrapper(
+    spec: &oci_spec::runtime::Spec,
+    index: usize,
+    options: &VirtiofsOptions,
+) -> Result<PathBuf> {
+    let args = options.virtiofsd_args();
+
+    if args.is_empty() {
+        return Ok(PathBuf::from("/crun-vm/virtiofsd"));
+    }
+
+    let path_in_container = PathBuf::from(format!("/crun-vm/virtiofsd-{index}"));
+    let path = spec.root_path().join(path_in_container.strip_prefix("/")?);
+
+    let args: Vec<String> = args.iter().map(|a| shell_quote(a)).collect();
+    fs::write(
+        &path,
+        format!(
+            "#!/bin/bash\nexec /crun-vm/virtiofsd {} \"$@\"\n",
+            args.join(" ")
+        ),
+    )?;
+    fs::set_permissions(&path, Permissions::from_mode(0o555))?;
+
+    Ok(path_in_container)
+}
+
 fn set_up_devices(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Result<()> {
     // set up block devices passed in using --device (note that rootless podman will turn those into
     // --mount/--volume instead)
@@ -342,7 +1550,10 @@ fn set_up_devices(spec: &mut oci_spec::runtime::Spec, mounts: &mut Mounts) -> Re
             is_regular_file: false,
             path_in_container,
             path_in_guest,
-            readonly: mode & 0o222 == 0,
+            options: DiskOptions {
+                readonly: mode & 0o222 == 0,
+                ..Default::default()
+            },
         });
     }
 
@@ -363,6 +1574,14 @@ fn set_up_blockdevs(
             "blockdev source must be a regular file or a block device"
         );
 
+        if meta.file_type().is_file() {
+            get_disk_image_file_format(
+                &blockdev.source,
+                Some(&blockdev.format),
+                blockdev.options.readonly,
+            )?;
+        }
+
         let path_in_container = PathBuf::from(format!(
             "crun-vm/mounts/block/{}",
             mounts.block_device.len()
@@ -371,13 +1590,18 @@ fn set_up_blockdevs(
 
         fs::create_dir_all(spec.root_path().join(&path_in_container).parent().unwrap())?;
 
+        let mut mount_options = vec!["bind".to_string(), "rprivate".to_string()];
+        if blockdev.options.readonly {
+            mount_options.push("ro".to_string());
+        }
+
         // mount from the host to the container
         spec.mounts_push(
             oci_spec::runtime::MountBuilder::default()
                 .typ("bind")
                 .source(blockdev.source.canonicalize()?)
                 .destination(&path_in_container)
-                .options(["bind".to_string(), "rprivate".to_string()])
+                .options(mount_options)
                 .build()
                 .unwrap(),
         );
@@ -388,13 +1612,107 @@ fn set_up_blockdevs(
             is_regular_file: meta.is_file(),
             path_in_container,
             path_in_guest,
-            readonly: false,
+            options: blockdev.options.clone(),
         });
     }
 
     Ok(())
 }
 
+fn set_up_scratch_disks(
+    spec: &mut oci_spec::runtime::Spec,
+    mounts: &mut Mounts,
+    custom_options: &CustomOptions,
+    rollback: &mut Rollback,
+) -> Result<()> {
+    for (i, scratch_disk) in custom_options.scratch_disk.iter().enumerate() {
+        let path_in_container = match &scratch_disk.persist {
+            Some(persist_path) => {
+                let path_in_container = PathBuf::from(format!(
+                    "crun-vm/mounts/block/{}",
+                    mounts.block_device.len()
+                ));
+
+                set_up_persisted_disk(
+                    spec,
+                    "scratch disk",
+                    persist_path,
+                    &scratch_disk.format,
+                    scratch_disk.size,
+                    &path_in_container,
+                    rollback,
+                )?;
+
+                path_in_container
+            }
+            None => {
+                // the container root is in the bundle, so the disk goes away with the container
+                let path_in_container = PathBuf::from(format!("crun-vm/scratch/{i}"));
+                let path = spec.root_path().join(&path_in_container);
+
+                fs::create_dir_all(path.parent().unwrap())?;
+                create_empty_vm_image(&path, &scratch_disk.format, scratch_disk.size)?;
+
+                path_in_container
+            }
+        };
+
+        mounts.block_device.push(BlockDeviceMount {
+            format: scratch_disk.format.clone(),
+            is_regular_file: true,
+            path_in_container,
+            path_in_guest: scratch_disk.target.clone(),
+            options: DiskOptions::default(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Bind mounts a disk image file on the host into the container, first creating it if it doesn't
+/// exist yet.
+fn set_up_persisted_disk(
+    spec: &mut oci_spec::runtime::Spec,
+    description: &str,
+    persist_path: &Path,
+    format: &str,
+    size: u64,
+    path_in_container: &Path,
+    rollback: &mut Rollback,
+) -> Result<()> {
+    if persist_path.try_exists()? {
+        // probe the format even though the disk is writable, as the result is only compared with
+        // the format the disk was created with
+        let actual_format = get_disk_image_file_format(persist_path, None, true)?;
+        ensure!(
+            actual_format == format,
+            "{description} {} has format {actual_format}, expected {format}",
+            persist_path.as_str(),
+        );
+    } else {
+        create_empty_vm_image(persist_path, format, size)?;
+
+        rollback.push({
+            let persist_path = persist_path.to_path_buf();
+            move || Ok(fs::remove_file(persist_path)?)
+        });
+    }
+
+    fs::create_dir_all(spec.root_path().join(path_in_container).parent().unwrap())?;
+
+    spec.mounts_push(
+        oci_spec::runtime::MountBuilder::default()
+            .typ("bind")
+            .source(persist_path.canonicalize()?)
+            .destination(path_in_container)
+            .options(["bind".to_string(), "rprivate".to_string()])
+            .build()
+            .unwrap(),
+    );
+
+    Ok(())
+}
+
 fn set_up_extra_container_mounts_and_devices(spec: &mut oci_spec::runtime::Spec) -> Result<()> {
     fn add_bind_mount(spec: &mut oci_spec::runtime::Spec, path: impl AsRef<Path>) {
         spec.mounts_push(
@@ -433,6 +1751,16 @@ fn set_up_extra_container_mounts_and_devices(spec: &mut oci_spec::runtime::Spec)
         add_bind_mount(spec, path);
     }
 
+    spec.mounts_push(
+        oci_spec::runtime::MountBuilder::default()
+            .typ("tmpfs")
+            .source("tmpfs")
+            .destination(KEYS_DIR_PATH_IN_CONTAINER)
+            .options(["nosuid", "nodev", "noexec", "mode=0700"].map(String::from))
+            .build()
+            .unwrap(),
+    );
+
     add_bind_mount(spec, "/dev/kvm");
     add_char_dev(spec, "/dev/kvm")?;
 
@@ -484,6 +1812,7 @@ fn set_up_first_boot_config(
         container_public_key: &container_public_key,
         password: custom_options.password.as_deref(),
         mounts,
+        grow_root: custom_options.disk_size.is_some(),
     };
 
     config
@@ -561,3 +1890,55 @@ fn adjust_container_resources(spec: &mut oci_spec::runtime::Spec) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn secrets_stay_in_container() {
+        let dir_path = env::temp_dir().join(format!("crun-vm-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir_path).unwrap();
+        fs::write(dir_path.join("key"), "passphrase").unwrap();
+        fs::write(dir_path.join("file"), "").unwrap();
+
+        let bind_mount = |source: &str, destination: &str| {
+            oci_spec::runtime::MountBuilder::default()
+                .typ("bind")
+                .source(dir_path.join(source))
+                .destination(destination)
+                .options(["bind".to_string(), "ro".to_string()])
+                .build()
+                .unwrap()
+        };
+
+        let mut spec = oci_spec::runtime::Spec::default();
+        spec.set_mounts(Some(vec![
+            bind_mount("key", "/run/secrets/vm-image-key"),
+            bind_mount("file", "/etc/my-file"),
+        ]));
+
+        let custom_options = CustomOptions::try_from(CustomOptionsRaw::default()).unwrap();
+        let mut mounts = Mounts::default();
+        set_up_mounts(&mut spec, &mut mounts, &custom_options).unwrap();
+
+        let files: Vec<&Path> = mounts
+            .file
+            .iter()
+            .map(|m| m.path_in_guest.as_path())
+            .collect();
+        assert_eq!(files, [Path::new("/etc/my-file")]);
+
+        let destinations: Vec<&Path> = spec
+            .mounts()
+            .iter()
+            .flatten()
+            .map(|m| m.destination().as_path())
+            .collect();
+        assert!(destinations.contains(&Path::new("/run/secrets/vm-image-key")));
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+}
@@ -1,9 +1,10 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
-use std::fs;
+use std::fs::{self, File};
 use std::path::Path;
 
 use anyhow::Result;
+use serde_json::Value;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum RuntimeEnv {
@@ -48,4 +49,155 @@ impl RuntimeEnv {
             Ok(RuntimeEnv::Other)
         }
     }
+
+    /// Returns the entrypoint of the container, as recorded by the container engine, or `None`
+    /// if it can't be found.
+    ///
+    /// With Docker, this is the entrypoint that the container was created with, *i.e.*, the one
+    /// set by --entrypoint or else the one in the container image config. Otherwise, this is the
+    /// entrypoint in the image config found in containers-storage.
+    pub fn entrypoint(
+        &self,
+        spec: &oci_spec::runtime::Spec,
+        bundle_path: &Path,
+    ) -> Result<Option<Vec<String>>> {
+        let entrypoint = match self {
+            RuntimeEnv::Docker => docker_entrypoint(spec)?,
+            RuntimeEnv::Kubernetes | RuntimeEnv::Other => {
+                containers_storage_entrypoint(bundle_path)?
+            }
+        };
+
+        Ok(entrypoint.map(|e| {
+            e.as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|arg| arg.as_str().map(String::from))
+                .collect()
+        }))
+    }
+}
+
+/// Gets the entrypoint from the container's `config.v2.json`, which lives in the same directory as
+/// the `/etc/hostname` file that Docker mounts into the container.
+fn docker_entrypoint(spec: &oci_spec::runtime::Spec) -> Result<Option<Value>> {
+    let config_path = spec
+        .mounts()
+        .iter()
+        .flatten()
+        .filter(|m| m.destination() == Path::new("/etc/hostname"))
+        .flat_map(|m| m.source())
+        .next()
+        .and_then(|p| p.parent())
+        .map(|p| p.join("config.v2.json"));
+
+    let config: Value = match config_path {
+        Some(path) if path.is_file() => serde_json::from_reader(File::open(path)?)?,
+        _ => return Ok(None),
+    };
+
+    Ok(Some(config["Config"]["Entrypoint"].clone()))
+}
+
+/// Gets the entrypoint from the config of the container's image in containers-storage, where the
+/// bundle is `<storage>/<driver>-containers/<container-id>/userdata`.
+fn containers_storage_entrypoint(bundle_path: &Path) -> Result<Option<Value>> {
+    let Some(containers_dir_path) = bundle_path.parent().and_then(|p| p.parent()) else {
+        return Ok(None);
+    };
+    let (Some(storage_path), Some(container_id), Some(driver)) = (
+        containers_dir_path.parent(),
+        bundle_path.parent().and_then(|p| p.file_name()),
+        containers_dir_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix("-containers")),
+    ) else {
+        return Ok(None);
+    };
+
+    let containers_file_path = containers_dir_path.join("containers.json");
+    if !containers_file_path.is_file() {
+        return Ok(None);
+    }
+
+    let containers: Value = serde_json::from_reader(File::open(containers_file_path)?)?;
+    let image_id = containers
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|c| c["id"].as_str() == container_id.to_str())
+        .and_then(|c| c["image"].as_str());
+
+    let Some(image_id) = image_id else {
+        return Ok(None);
+    };
+
+    // The image's manifest and config are kept alongside other data about the image, and the
+    // manifest gives the digest under which the config is stored.
+    let image_dir_path = storage_path.join(format!("{driver}-images")).join(image_id);
+    let manifest_path = image_dir_path.join(big_data_file_name("manifest"));
+    if !manifest_path.is_file() {
+        return Ok(None);
+    }
+
+    let manifest: Value = serde_json::from_reader(File::open(manifest_path)?)?;
+    let Some(config_digest) = manifest["config"]["digest"].as_str() else {
+        return Ok(None);
+    };
+
+    let config_path = image_dir_path.join(big_data_file_name(config_digest));
+    if !config_path.is_file() {
+        return Ok(None);
+    }
+
+    let config: Value = serde_json::from_reader(File::open(config_path)?)?;
+    Ok(Some(config["config"]["Entrypoint"].clone()))
+}
+
+/// Returns the name of the file in which containers-storage keeps the image data with the given
+/// key. Keys with characters other than lowercase letters, digits, and dots are base64-encoded.
+fn big_data_file_name(key: &str) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    if key
+        .chars()
+        .all(|c| c == '.' || c.is_ascii_digit() || c.is_ascii_lowercase())
+    {
+        return key.to_string();
+    }
+
+    let mut name = "=".to_string();
+
+    for chunk in key.as_bytes().chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            name.push(match i <= chunk.len() {
+                true => ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char,
+                false => '=',
+            });
+        }
+    }
+
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("manifest", "manifest"; "plain")]
+    #[test_case("sha256:ab", "=c2hhMjU2OmFi"; "no padding")]
+    #[test_case("sha256:a", "=c2hhMjU2OmE="; "one padding character")]
+    #[test_case("Manifest", "=TWFuaWZlc3Q="; "uppercase")]
+    #[test_case("sha256:abc", "=c2hhMjU2OmFiYw=="; "two padding characters")]
+    fn big_data_file_names(key: &str, expected: &str) {
+        assert_eq!(big_data_file_name(key), expected);
+    }
 }
@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::commands::create::{
+    ROOT_DIR_NAME, VM_IMAGE_CACHE_DIR_PATH_IN_CONTAINER, VM_IMAGE_DIR_PATH_IN_CONTAINER,
+    VM_IMAGE_PRIVATE_DIR_NAME,
+};
+use crate::crun::{crun, crun_state_bundle};
+use crate::util::{remove_dir_all_if_exists, unmount_if_mounted};
+
+pub fn delete(
+    global_args: &liboci_cli::GlobalOpts,
+    args: &liboci_cli::Delete,
+    raw_args: &[OsString],
+) -> Result<()> {
+    // If this fails, the container doesn't exist or wasn't fully created, and crun will complain
+    // appropriately below.
+    let bundle_path = crun_state_bundle(global_args, &args.container_id).ok();
+
+    crun(raw_args)?;
+
+    if let Some(bundle_path) = bundle_path {
+        clean_up(&bundle_path)
+            .with_context(|| format!("failed to clean up bundle {}", bundle_path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Undoes what `create` did outside of the container, tolerating any of it not having been done.
+fn clean_up(bundle_path: &Path) -> Result<()> {
+    let root_path = bundle_path.join(ROOT_DIR_NAME);
+
+    // a lazy unmount also takes care of the VM image file bind mounts underneath
+    unmount_if_mounted(root_path.join(VM_IMAGE_DIR_PATH_IN_CONTAINER))?;
+
+    if let Ok(entries) = root_path
+        .join(VM_IMAGE_CACHE_DIR_PATH_IN_CONTAINER)
+        .read_dir()
+    {
+        for entry in entries {
+            unmount_if_mounted(entry?.path())?;
+        }
+    }
+
+    let private_dir_path = bundle_path.join(VM_IMAGE_PRIVATE_DIR_NAME);
+
+    match fs::read_link(&private_dir_path) {
+        Ok(target) => {
+            remove_dir_all_if_exists(target)?;
+            fs::remove_file(&private_dir_path)?;
+        }
+        Err(_) => remove_dir_all_if_exists(&private_dir_path)?,
+    }
+
+    Ok(())
+}
@@ -7,6 +7,7 @@ use std::{
 
 use anyhow::Result;
 
+use crate::config::HostConfig;
 use crate::crun::crun_exec;
 
 pub fn exec(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Exec) -> Result<()> {
@@ -25,16 +26,15 @@ pub fn exec(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Exec) -> Re
     let mut new_command = vec![];
 
     if ssh_user != "-" {
-        new_command.extend([
-            "ssh".to_string(),
-            "-o".to_string(),
-            "LogLevel=ERROR".to_string(),
-            "-o".to_string(),
-            "StrictHostKeyChecking=no".to_string(),
-            "-l".to_string(),
-            ssh_user.clone(),
-            "localhost".to_string(),
-        ]);
+        let host_config = HostConfig::load()?;
+
+        new_command.push("ssh".to_string());
+
+        for option in &host_config.exec.ssh_options {
+            new_command.extend(["-o".to_string(), option.clone()]);
+        }
+
+        new_command.extend(["-l".to_string(), ssh_user.clone(), "localhost".to_string()]);
     }
 
     new_command.extend(command.iter().skip(1).cloned());
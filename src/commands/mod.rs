@@ -1,4 +1,7 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+pub mod backup;
+pub mod commit;
 pub mod create;
+pub mod delete;
 pub mod exec;
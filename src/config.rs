@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use serde::Deserialize;
+
+use crate::commands::create::CustomOptionsRaw;
+use crate::util::{ByteSize, PathExt};
+
+/// Host-wide configuration.
+///
+/// This is loaded from the system config file and then the per-user config file, with settings in
+/// the latter overriding those in the former. Settings not given in either take the values below.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HostConfig {
+    /// Defaults for custom options, overridden by the VM image config file and then by options
+    /// given to the container.
+    pub options: CustomOptionsRaw,
+
+    /// Path to the QEMU binary that libvirt should use.
+    pub emulator: PathBuf,
+
+    /// Path to the virtiofsd binary used to expose directories to the VM.
+    pub virtiofsd: PathBuf,
+
+    /// Directory in which to cache data derived from VM images, *e.g.*, decompressed images.
+    pub cache_dir: PathBuf,
+
+    /// Size beyond which the least recently used entries are evicted from the cache directory.
+    pub cache_max_size: ByteSize,
+
+    pub exec: ExecConfig,
+}
+
+impl Default for HostConfig {
+    fn default() -> Self {
+        Self {
+            options: CustomOptionsRaw::default(),
+            emulator: PathBuf::from("/usr/bin/qemu-system-x86_64"),
+            virtiofsd: PathBuf::from("/usr/libexec/virtiofsd"),
+            cache_dir: default_cache_dir(),
+            cache_max_size: ByteSize(20 << 30),
+            exec: ExecConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExecConfig {
+    /// Options passed to `ssh` using `-o` when exec'ing into the VM.
+    pub ssh_options: Vec<String>,
+}
+
+impl Default for ExecConfig {
+    fn default() -> Self {
+        Self {
+            ssh_options: vec![
+                "LogLevel=ERROR".to_string(),
+                "StrictHostKeyChecking=no".to_string(),
+            ],
+        }
+    }
+}
+
+impl HostConfig {
+    pub fn load() -> Result<HostConfig> {
+        let mut paths = vec![PathBuf::from("/etc/crun-vm/crun-vm.conf")];
+
+        let user_config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+            .or_else(|| home::home_dir().map(|p| p.join(".config")));
+
+        if let Some(dir) = user_config_dir {
+            paths.push(dir.join("crun-vm/crun-vm.conf"));
+        }
+
+        Self::load_from(&paths)
+    }
+
+    fn load_from(paths: &[impl AsRef<Path>]) -> Result<HostConfig> {
+        // Merge the files at the YAML level, so that the per-user file may override individual
+        // settings in the system file.
+
+        let mut merged = serde_yaml::Value::Null;
+
+        for path in paths {
+            let path = path.as_ref();
+
+            if !path.try_exists()? {
+                continue;
+            }
+
+            let value: serde_yaml::Value = serde_yaml::from_str(&fs::read_to_string(path)?)
+                .with_context(|| format!("invalid config file {}", path.as_str()))?;
+
+            merged = merge_yaml(merged, value);
+        }
+
+        let config: HostConfig = match merged {
+            serde_yaml::Value::Null => HostConfig::default(),
+            value => serde_yaml::from_value(value).context("invalid crun-vm config file")?,
+        };
+
+        ensure!(
+            config.emulator.is_absolute()
+                && config.virtiofsd.is_absolute()
+                && config.cache_dir.is_absolute()
+                && config.options.paths().iter().all(|p| p.is_absolute()),
+            "paths in crun-vm config files must be absolute"
+        );
+
+        // these binaries run in the container, which only has these host directories
+        const HOST_BINARY_DIRS: [&str; 4] = ["/bin", "/lib", "/lib64", "/usr"];
+
+        for (name, path) in [
+            ("emulator", &config.emulator),
+            ("virtiofsd", &config.virtiofsd),
+        ] {
+            ensure!(
+                HOST_BINARY_DIRS.iter().any(|dir| path.starts_with(dir))
+                    && !path.components().any(|c| c == Component::ParentDir),
+                "{name} path {} in crun-vm config files must be under {}, since only those host \
+                directories are available in the container",
+                path.as_str(),
+                HOST_BINARY_DIRS.join(", ")
+            );
+        }
+
+        Ok(config)
+    }
+}
+
+fn default_cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| home::home_dir().map(|p| p.join(".cache")))
+        .map(|p| p.join("crun-vm"))
+        .unwrap_or_else(|| PathBuf::from("/var/cache/crun-vm"))
+}
+
+fn merge_yaml(base: serde_yaml::Value, overlay: serde_yaml::Value) -> serde_yaml::Value {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(mut base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                let merged = match base.remove(&key) {
+                    Some(base_value) => merge_yaml(base_value, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            serde_yaml::Value::Mapping(base)
+        }
+        (base, serde_yaml::Value::Null) => base,
+        (_, overlay) => overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(s: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn merge_yaml_overrides_nested_settings() {
+        let base = yaml("emulator: /usr/bin/a\noptions:\n  memory: 1GiB\n  password: x\n");
+        let overlay = yaml("options:\n  memory: 2GiB\nvirtiofsd: /usr/bin/b\n");
+
+        assert_eq!(
+            merge_yaml(base, overlay),
+            yaml(
+                "emulator: /usr/bin/a\noptions:\n  memory: 2GiB\n  password: x\n\
+                virtiofsd: /usr/bin/b\n"
+            )
+        );
+    }
+
+    #[test]
+    fn merge_yaml_keeps_base_for_empty_overlay() {
+        let base = yaml("emulator: /usr/bin/a\n");
+        assert_eq!(merge_yaml(base.clone(), serde_yaml::Value::Null), base);
+    }
+
+    #[test]
+    fn merge_yaml_replaces_non_mappings() {
+        let base = yaml("exec:\n  ssh-options: [a, b]\n");
+        let overlay = yaml("exec:\n  ssh-options: [c]\n");
+        assert_eq!(merge_yaml(base, overlay.clone()), overlay);
+    }
+}
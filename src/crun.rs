@@ -1,9 +1,11 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
 use std::ffi::OsStr;
-use std::process::Command;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 
 use anyhow::{ensure, Result};
+use serde::Deserialize;
 
 use crate::util::PathExt;
 
@@ -23,38 +25,15 @@ pub fn crun(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
 pub fn crun_create(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Create) -> Result<()> {
     // build crun argument list
 
-    let mut arg_list = Vec::<String>::new();
+    let mut arg_list = global_arg_list(global_args);
     let mut arg = |arg: &str| {
         arg_list.push(arg.to_string());
     };
 
-    if global_args.debug {
-        arg("--debug");
-    }
-
-    if let Some(path) = &global_args.log {
-        arg("--log");
-        arg(path.as_str());
-    }
-
-    if let Some(format) = &global_args.log_format {
-        arg("--log-format");
-        arg(format);
-    }
-
     if args.no_pivot {
         arg("--no-pivot");
     }
 
-    if let Some(path) = &global_args.root {
-        arg("--root");
-        arg(path.as_str());
-    }
-
-    if global_args.systemd_cgroup {
-        arg("--systemd-cgroup");
-    }
-
     arg("create");
 
     arg("--bundle");
@@ -87,34 +66,11 @@ pub fn crun_create(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Crea
 pub fn crun_exec(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Exec) -> Result<()> {
     // build crun argument list
 
-    let mut arg_list = Vec::<String>::new();
+    let mut arg_list = global_arg_list(global_args);
     let mut arg = |arg: &str| {
         arg_list.push(arg.to_string());
     };
 
-    if global_args.debug {
-        arg("--debug");
-    }
-
-    if let Some(path) = &global_args.log {
-        arg("--log");
-        arg(path.as_str());
-    }
-
-    if let Some(format) = &global_args.log_format {
-        arg("--log-format");
-        arg(format);
-    }
-
-    if let Some(path) = &global_args.root {
-        arg("--root");
-        arg(path.as_str());
-    }
-
-    if global_args.systemd_cgroup {
-        arg("--systemd-cgroup");
-    }
-
     arg("exec");
 
     if let Some(profile) = &args.apparmor {
@@ -193,3 +149,81 @@ pub fn crun_exec(global_args: &liboci_cli::GlobalOpts, args: &liboci_cli::Exec)
 
     crun(arg_list)
 }
+
+/// Returns the bundle path of the given container, as reported by `crun state`.
+pub fn crun_state_bundle(
+    global_args: &liboci_cli::GlobalOpts,
+    container_id: &str,
+) -> Result<PathBuf> {
+    #[derive(Deserialize)]
+    struct State {
+        bundle: PathBuf,
+    }
+
+    let output = Command::new("crun")
+        .args(global_arg_list(global_args))
+        .arg("state")
+        .arg(container_id)
+        .stderr(Stdio::null())
+        .output()?;
+
+    ensure!(output.status.success(), "crun failed");
+
+    let state: State = serde_json::from_slice(&output.stdout)?;
+    Ok(state.bundle)
+}
+
+/// Runs a command in the given container with `crun exec`, returning its standard output.
+pub fn crun_exec_output(
+    global_args: &liboci_cli::GlobalOpts,
+    container_id: &str,
+    command: &[&str],
+) -> Result<String> {
+    let output = Command::new("crun")
+        .args(global_arg_list(global_args))
+        .arg("exec")
+        .arg(container_id)
+        .args(command)
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    ensure!(
+        output.status.success(),
+        "`{}` failed in the container",
+        command.join(" ")
+    );
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn global_arg_list(global_args: &liboci_cli::GlobalOpts) -> Vec<String> {
+    let mut arg_list = Vec::<String>::new();
+    let mut arg = |arg: &str| {
+        arg_list.push(arg.to_string());
+    };
+
+    if global_args.debug {
+        arg("--debug");
+    }
+
+    if let Some(path) = &global_args.log {
+        arg("--log");
+        arg(path.as_str());
+    }
+
+    if let Some(format) = &global_args.log_format {
+        arg("--log-format");
+        arg(format);
+    }
+
+    if let Some(path) = &global_args.root {
+        arg("--root");
+        arg(path.as_str());
+    }
+
+    if global_args.systemd_cgroup {
+        arg("--systemd-cgroup");
+    }
+
+    arg_list
+}
@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
 mod commands;
+mod config;
 mod crun;
 mod util;
 
@@ -29,6 +30,10 @@ enum Command {
 
     #[clap(flatten)]
     Common(Box<liboci_cli::CommonCmd>),
+
+    Commit(commands::commit::Commit),
+
+    Backup(commands::backup::Backup),
 }
 
 pub fn main(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
@@ -41,16 +46,24 @@ pub fn main(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
         Args::parse_from(iter::once(&OsStr::new("crun-vm").to_os_string()).chain(&args));
 
     match parsed_args.command {
-        Command::Standard(cmd) => {
-            if let liboci_cli::StandardCmd::Create(create_args) = *cmd {
+        Command::Standard(cmd) => match *cmd {
+            liboci_cli::StandardCmd::Create(create_args) => {
                 return commands::create::create(&parsed_args.global, &create_args);
             }
-        }
+            liboci_cli::StandardCmd::Delete(delete_args) => {
+                return commands::delete::delete(&parsed_args.global, &delete_args, &args);
+            }
+            _ => {}
+        },
         Command::Common(cmd) => {
             if let liboci_cli::CommonCmd::Exec(exec_args) = *cmd {
                 return commands::exec::exec(&parsed_args.global, &exec_args);
             }
         }
+        Command::Commit(commit_args) => return commands::commit::commit(&commit_args),
+        Command::Backup(backup_args) => {
+            return commands::backup::backup(&parsed_args.global, &backup_args);
+        }
     }
 
     // not a command we implement ourselves, just pass it on to crun
@@ -1,23 +1,27 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
 use std::ffi::{c_char, CString};
-use std::fs::{self, OpenOptions, Permissions};
-use std::io;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{self, Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, bail, ensure, Result};
-use nix::mount::MsFlags;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::mount::{MntFlags, MsFlags};
+use nix::sys::stat::futimens;
+use nix::sys::time::TimeSpec;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 pub trait PathExt {
     fn as_str(&self) -> &str;
-
-    fn as_string(&self) -> String {
-        self.as_str().to_string()
-    }
 }
 
 impl<P: AsRef<Path>> PathExt for P {
@@ -26,6 +30,63 @@ impl<P: AsRef<Path>> PathExt for P {
     }
 }
 
+/// A size in bytes, given as a number optionally followed by a `K`, `M`, `G`, or `T` suffix
+/// (optionally followed by `iB` or `B`), which always denote powers of 1024.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ByteSize> {
+        let s = s.trim();
+        let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, suffix) = s.split_at(digits_end);
+
+        let shift = match suffix
+            .trim_start()
+            .trim_end_matches("iB")
+            .trim_end_matches('B')
+        {
+            "" => 0,
+            "K" | "k" => 10,
+            "M" => 20,
+            "G" => 30,
+            "T" => 40,
+            _ => bail!("invalid size '{s}'"),
+        };
+
+        let size = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(1 << shift))
+            .ok_or_else(|| anyhow!("invalid size '{s}'"))?;
+
+        Ok(ByteSize(size))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u64),
+            String(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(n) => Ok(ByteSize(n)),
+            Raw::String(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Quotes `s` so that it is interpreted as a single word by the shell.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
 pub fn set_file_context(path: impl AsRef<Path>, context: &str) -> Result<()> {
     extern "C" {
         fn setfilecon(path: *const c_char, con: *const c_char) -> i32;
@@ -41,6 +102,69 @@ pub fn set_file_context(path: impl AsRef<Path>, context: &str) -> Result<()> {
     Ok(())
 }
 
+/// Actions that undo side effects performed so far, which are run in reverse order when this is
+/// dropped, unless `commit()` was called.
+#[derive(Default)]
+pub struct Rollback {
+    actions: Vec<Box<dyn FnOnce() -> Result<()>>>,
+}
+
+impl Rollback {
+    pub fn push(&mut self, action: impl FnOnce() -> Result<()> + 'static) {
+        self.actions.push(Box::new(action));
+    }
+
+    /// Keeps all side effects performed so far.
+    pub fn commit(mut self) {
+        self.actions.clear();
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        for action in self.actions.drain(..).rev() {
+            if let Err(e) = action() {
+                eprintln!("warning: failed to roll back: {e:#}");
+            }
+        }
+    }
+}
+
+/// Unmounts the given path, along with any mounts underneath it, doing nothing if it isn't a mount
+/// point or doesn't exist.
+pub fn unmount_if_mounted(path: impl AsRef<Path>) -> Result<()> {
+    match nix::mount::umount2(path.as_ref(), MntFlags::MNT_DETACH) {
+        Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("failed to unmount {}", path.as_str())),
+    }
+}
+
+pub fn remove_dir_all_if_exists(path: impl AsRef<Path>) -> Result<()> {
+    match fs::remove_dir_all(path.as_ref()) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("failed to remove {}", path.as_str()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Takes an exclusive advisory lock on the given file, creating it if it doesn't exist.
+///
+/// Returns `None` if the lock is held by someone else. Otherwise, the lock is held until the
+/// returned `File` is closed.
+pub fn try_lock_file(path: impl AsRef<Path>) -> Result<Option<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.as_ref())?;
+
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(Some(file)),
+        Err(Errno::EWOULDBLOCK) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
 pub fn bind_mount_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
     // ensure target exists
 
@@ -249,11 +373,13 @@ impl SpecExt for oci_spec::runtime::Spec {
     }
 }
 
-pub fn find_single_file_in_dirs(
+/// Returns the regular files in the given directories that aren't in `ignore_files`, sorted by
+/// path.
+pub fn find_files_in_dirs(
     dir_paths: impl IntoIterator<Item = impl AsRef<Path>>,
     ignore_files: &[impl AsRef<Path>],
-) -> Result<PathBuf> {
-    let mut candidate: Option<PathBuf> = None;
+) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
 
     for dir_path in dir_paths {
         let dir_path = dir_path.as_ref();
@@ -272,17 +398,16 @@ pub fn find_single_file_in_dirs(
                     continue; // file is in `ignore_files`
                 }
 
-                ensure!(candidate.is_none(), "more than one file found");
-
-                candidate = Some(path);
+                files.push(path);
             }
         }
     }
 
-    candidate.ok_or_else(|| anyhow!("no files found"))
+    files.sort();
+    Ok(files)
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct VmImageInfo {
     #[serde(skip)]
     pub path: PathBuf,
@@ -291,14 +416,56 @@ pub struct VmImageInfo {
     pub size: u64,
 
     pub format: String,
+
+    #[serde(rename = "backing-filename", default)]
+    pub backing_file: Option<PathBuf>,
+
+    /// Files other than the image itself that it takes data from, *i.e.*, the external data file
+    /// of a qcow2 image, or the extents of a VMDK image that are separate files, as is always the
+    /// case for a VMDK descriptor file.
+    #[serde(
+        rename = "format-specific",
+        default,
+        deserialize_with = "deserialize_data_files"
+    )]
+    pub external_files: Vec<PathBuf>,
+
+    /// Whether the image is encrypted, *e.g.*, a LUKS-encrypted qcow2 image.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// Gets the qcow2 data file and the VMDK extents, which for VMDK images with embedded descriptors
+/// include the image itself.
+fn deserialize_data_files<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<PathBuf>, D::Error> {
+    let format_specific = serde_json::Value::deserialize(deserializer)?;
+    let data = &format_specific["data"];
+
+    let data_file = data["data-file"].as_str();
+    let extents = data["extents"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|extent| extent["filename"].as_str());
+
+    Ok(data_file
+        .into_iter()
+        .chain(extents)
+        .map(PathBuf::from)
+        .collect())
 }
 
 impl VmImageInfo {
     pub fn of(vm_image_path: impl AsRef<Path>) -> Result<VmImageInfo> {
         let vm_image_path = vm_image_path.as_ref().to_path_buf();
 
+        // --force-share so that this works on images in use by running VMs, and doesn't get in the
+        // way of them starting
         let output = Command::new("qemu-img")
             .arg("info")
+            .arg("--force-share")
             .arg("--output=json")
             .arg(vm_image_path.as_os_str())
             .stdout(Stdio::piped())
@@ -307,15 +474,362 @@ impl VmImageInfo {
         ensure!(output.status.success(), "`qemu-img info` failed");
 
         let mut info: VmImageInfo = serde_json::from_slice(&output.stdout)?;
+
+        let metadata = fs::metadata(&vm_image_path)?;
+        info.external_files.retain(|path| {
+            !fs::metadata(path)
+                .is_ok_and(|m| m.dev() == metadata.dev() && m.ino() == metadata.ino())
+        });
+
         info.path = vm_image_path;
 
         Ok(info)
     }
 }
 
+/// Whether the given file is an ISO 9660 image, *e.g.*, an OS installer.
+pub fn is_iso_image(path: impl AsRef<Path>) -> Result<bool> {
+    // the first volume descriptor starts at 32 KiB and has this signature after its type byte
+    const SIGNATURE_OFFSET: u64 = 0x8001;
+    const SIGNATURE: &[u8; 5] = b"CD001";
+
+    let mut file = File::open(path)?;
+    let mut buf = [0; 5];
+
+    file.seek(SeekFrom::Start(SIGNATURE_OFFSET))?;
+    match file.read_exact(&mut buf) {
+        Ok(()) => Ok(&buf == SIGNATURE),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the backing file name recorded in the header of the given file, if it is a qcow2 image
+/// that has one.
+///
+/// This reads the header directly rather than using `qemu-img`, so it is fine to use on files that
+/// aren't trusted yet.
+pub fn get_qcow2_backing_file(path: impl AsRef<Path>) -> Result<Option<String>> {
+    // qemu refuses longer backing file names
+    const MAX_BACKING_FILE_SIZE: u32 = 1023;
+
+    let mut file = File::open(path)?;
+    let mut header = [0; 20];
+
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let backing_file_offset = u64::from_be_bytes(header[8..16].try_into().unwrap());
+    let backing_file_size = u32::from_be_bytes(header[16..20].try_into().unwrap());
+
+    if &header[..4] != b"QFI\xfb" || backing_file_offset == 0 || backing_file_size == 0 {
+        return Ok(None);
+    }
+
+    ensure!(
+        backing_file_size <= MAX_BACKING_FILE_SIZE,
+        "invalid qcow2 image header"
+    );
+
+    let mut backing_file = vec![0; backing_file_size as usize];
+    file.seek(SeekFrom::Start(backing_file_offset))?;
+    file.read_exact(&mut backing_file)?;
+
+    Ok(Some(
+        String::from_utf8(backing_file).context("invalid qcow2 image header")?,
+    ))
+}
+
+/// Returns the program that decompresses the given file, if it is compressed.
+pub fn get_decompressor(path: impl AsRef<Path>) -> Result<Option<&'static str>> {
+    const MAGIC_NUMBERS: [(&[u8], &str); 4] = [
+        (b"\x1f\x8b", "gzip"),
+        (b"\xfd7zXZ\x00", "xz"),
+        (b"\x28\xb5\x2f\xfd", "zstd"),
+        (b"BZh", "bzip2"),
+    ];
+
+    let mut buf = vec![];
+    File::open(path)?.take(6).read_to_end(&mut buf)?;
+
+    Ok(MAGIC_NUMBERS
+        .iter()
+        .find(|(magic, _)| buf.starts_with(magic))
+        .map(|(_, program)| *program))
+}
+
+/// Decompresses file `from` into `to` using the given program, which must accept `-dc` like gzip.
+pub fn decompress_file(from: &Path, to: &Path, decompressor: &str) -> Result<()> {
+    let status = Command::new(decompressor)
+        .arg("-dc")
+        .arg(from)
+        .stdout(File::create(to)?)
+        .status()
+        .with_context(|| format!("failed to run {decompressor}"))?;
+
+    ensure!(
+        status.success(),
+        "`{decompressor}` failed to decompress {}",
+        from.as_str()
+    );
+
+    Ok(())
+}
+
+/// Validates a hex-encoded SHA-256 digest, returning it in lowercase.
+pub fn parse_sha256_digest(hex: &str) -> Result<String> {
+    ensure!(
+        hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        "invalid SHA-256 digest {hex}"
+    );
+
+    Ok(hex.to_ascii_lowercase())
+}
+
+/// Returns the hex-encoded SHA-256 digest of the given file.
+///
+/// Digests are remembered in `cache_dir` by `digest_cache_key`, so that large files aren't rehashed
+/// every time.
+pub fn get_file_digest(path: impl AsRef<Path>, cache_dir: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+    let metadata = path.metadata()?;
+
+    let digests_dir = cache_dir.as_ref().join("digests");
+    let cache_path = digests_dir.join(digest_cache_key(&metadata));
+
+    if let Ok(mut file) = File::open(&cache_path) {
+        let mut digest = String::new();
+        file.read_to_string(&mut digest)?;
+        touch(&file)?;
+        return Ok(digest);
+    }
+
+    let digest = compute_file_digest(path)?;
+
+    // write atomically, as other containers may be looking up the same file concurrently
+    fs::create_dir_all(&digests_dir)?;
+    let temp_path = cache_path.with_extension(format!("tmp-{}", process::id()));
+    fs::write(&temp_path, &digest)?;
+    fs::rename(&temp_path, &cache_path)?;
+
+    evict_cached_digests(&digests_dir)?;
+
+    Ok(digest)
+}
+
+/// Identifies a file by its inode number, size, and modification and change times.
+///
+/// The device number is left out, as files in container images are accessed through an overlayfs
+/// mount with a different device number for each container, which would make the cache never hit.
+/// Inode numbers alone may be reused across file systems, but the change time, which can't be set
+/// at will, makes it very unlikely for another file to have the same key.
+fn digest_cache_key(metadata: &impl MetadataExt) -> String {
+    format!(
+        "{}-{}-{}-{}-{}-{}",
+        metadata.ino(),
+        metadata.size(),
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+        metadata.ctime(),
+        metadata.ctime_nsec()
+    )
+}
+
+/// Maximum number of file digests to remember. Each takes up very little space, but they would
+/// otherwise pile up as images are updated.
+const MAX_CACHED_DIGESTS: usize = 1024;
+
+/// Forgets the least recently used digests in excess of `MAX_CACHED_DIGESTS`.
+fn evict_cached_digests(digests_dir: &Path) -> Result<()> {
+    let mut entries = vec![];
+
+    for entry in digests_dir.read_dir()? {
+        let entry = entry?;
+        match entry.metadata() {
+            Ok(metadata) => entries.push((metadata.modified()?, entry.path())),
+            // may have been concurrently evicted by another container
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if entries.len() > MAX_CACHED_DIGESTS {
+        entries.sort();
+
+        for (_, path) in &entries[..entries.len() - MAX_CACHED_DIGESTS] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the modification time of the given file to now.
+fn touch(file: &File) -> Result<()> {
+    let now = TimeSpec::from(SystemTime::now().duration_since(UNIX_EPOCH)?);
+    futimens(file.as_raw_fd(), &now, &now)?;
+    Ok(())
+}
+
+/// Subdirectories of the cache directory whose entries are subject to `evict_cache_entries`.
+const CACHE_ENTRY_DIRS: [&str; 2] = ["decompressed", "appliances"];
+
+/// Entries used more recently than this are never evicted, so that containers that were just
+/// created but not yet started, and thus don't hold a lock on their entries yet, keep them.
+const CACHE_ENTRY_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A shared lock on a cache entry, which keeps it from being evicted for as long as it is held.
+pub struct CacheEntryLock {
+    /// Path of the lock file, which other processes may also lock to keep the entry.
+    pub path: PathBuf,
+    _file: File,
+}
+
+/// Takes a shared lock on the cache entry with the given path, which need not exist yet, and
+/// marks it as used now.
+pub fn lock_cache_entry(entry_path: &Path) -> Result<CacheEntryLock> {
+    let path = entry_path.with_extension("lock");
+    fs::create_dir_all(path.parent().unwrap())?;
+
+    loop {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        flock(file.as_raw_fd(), FlockArg::LockShared)?;
+
+        // eviction removes the lock file while holding an exclusive lock on it, in which case we
+        // must try again with a new one
+        if is_same_file(&file, &path)? {
+            touch(&file)?;
+            return Ok(CacheEntryLock { path, _file: file });
+        }
+    }
+}
+
+fn is_same_file(file: &File, path: &Path) -> Result<bool> {
+    let metadata = file.metadata()?;
+
+    match path.metadata() {
+        Ok(m) => Ok(m.dev() == metadata.dev() && m.ino() == metadata.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes the least recently used decompressed VM images and unpacked appliances from the cache
+/// until they take up at most `max_size` bytes.
+///
+/// Entries that are locked by `lock_cache_entry` or that were used in the last hour are kept.
+pub fn evict_cache_entries(cache_dir: &Path, max_size: u64) -> Result<()> {
+    let mut entries = vec![];
+
+    for dir_path in CACHE_ENTRY_DIRS.map(|d| cache_dir.join(d)) {
+        if !dir_path.is_dir() {
+            continue;
+        }
+
+        for entry in dir_path.read_dir()? {
+            let path = entry?.path();
+
+            // skip lock files and entries still being created
+            if path.extension().is_some() || !path.is_dir() {
+                continue;
+            }
+
+            let lock_path = path.with_extension("lock");
+            let last_used = match lock_path.metadata() {
+                Ok(metadata) => metadata.modified()?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => UNIX_EPOCH,
+                Err(e) => return Err(e.into()),
+            };
+
+            entries.push((last_used, disk_usage(&path)?, path, lock_path));
+        }
+    }
+
+    let mut total_size: u64 = entries.iter().map(|(_, size, _, _)| size).sum();
+    entries.sort();
+
+    for (last_used, size, path, lock_path) in entries {
+        if total_size <= max_size || last_used.elapsed().unwrap_or_default() < CACHE_ENTRY_MIN_AGE {
+            break;
+        }
+
+        let Some(lock) = try_lock_file(&lock_path)? else {
+            continue; // in use
+        };
+
+        // another container may have concurrently evicted the entry and recreated it
+        if !is_same_file(&lock, &lock_path)? {
+            continue;
+        }
+
+        remove_dir_all_if_exists(&path)?;
+        fs::remove_file(&lock_path)?;
+        total_size -= size;
+    }
+
+    Ok(())
+}
+
+/// Returns the space that the files in the given directory take up on disk, which for sparse files
+/// may be less than their size.
+fn disk_usage(path: &Path) -> Result<u64> {
+    let mut usage = 0;
+
+    for entry in path.read_dir()? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        usage += if metadata.is_dir() {
+            disk_usage(&entry.path())?
+        } else {
+            metadata.blocks() * 512
+        };
+    }
+
+    Ok(usage)
+}
+
+/// Returns the hex-encoded SHA-256 digest of the given file, without caching it.
+pub fn compute_file_digest(path: impl AsRef<Path>) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Creates an empty, sparse VM image.
+pub fn create_empty_vm_image(path: &Path, format: &str, size: u64) -> Result<()> {
+    let status = Command::new("qemu-img")
+        .arg("create")
+        .arg("-q")
+        .arg("-f")
+        .arg(format)
+        .arg(path)
+        .arg(size.to_string())
+        .spawn()?
+        .wait()?;
+
+    ensure!(status.success(), "`qemu-img create` failed");
+
+    Ok(())
+}
+
+/// Creates a qcow2 overlay of the given base image with the given virtual size, which must not be
+/// smaller than the base image's.
 pub fn create_overlay_vm_image(
     overlay_vm_image_path: &Path,
     base_vm_image_info: &VmImageInfo,
+    size: u64,
 ) -> Result<()> {
     let status = Command::new("qemu-img")
         .arg("create")
@@ -328,7 +842,7 @@ pub fn create_overlay_vm_image(
         .arg("-b")
         .arg(&base_vm_image_info.path)
         .arg(overlay_vm_image_path)
-        .arg(base_vm_image_info.size.to_string())
+        .arg(size.to_string())
         .spawn()?
         .wait()?;
 
@@ -336,3 +850,213 @@ pub fn create_overlay_vm_image(
 
     Ok(())
 }
+
+/// Converts a VM image of any format QEMU supports into a standalone qcow2 image.
+pub fn convert_vm_image(from: &Path, from_format: &str, to: &Path) -> Result<()> {
+    let status = Command::new("qemu-img")
+        .arg("convert")
+        .arg("-q")
+        .arg("-f")
+        .arg(from_format)
+        .arg("-O")
+        .arg("qcow2")
+        .arg(from)
+        .arg(to)
+        .spawn()?
+        .wait()?;
+
+    ensure!(status.success(), "`qemu-img convert` failed");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("123", 123; "bytes")]
+    #[test_case("4K", 4 << 10; "kibibytes")]
+    #[test_case("4k", 4 << 10; "lowercase kibibytes")]
+    #[test_case("2M", 2 << 20; "mebibytes")]
+    #[test_case("2 GiB", 2 << 30; "gibibytes with space")]
+    #[test_case("1TB", 1 << 40; "tebibytes")]
+    fn byte_size_valid(s: &str, expected: u64) {
+        assert_eq!(s.parse::<ByteSize>().unwrap(), ByteSize(expected));
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("G"; "no number")]
+    #[test_case("1.5G"; "fraction")]
+    #[test_case("-1"; "negative")]
+    #[test_case("3P"; "unknown suffix")]
+    #[test_case("99999999999T"; "overflow")]
+    fn byte_size_invalid(s: &str) {
+        assert!(s.parse::<ByteSize>().is_err(), "{s}");
+    }
+
+    #[test]
+    fn sha256_digest_valid() {
+        let digest = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        assert_eq!(
+            parse_sha256_digest(digest).unwrap(),
+            digest.to_ascii_lowercase()
+        );
+    }
+
+    #[test_case(""; "empty")]
+    #[test_case("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85"; "too short")]
+    #[test_case("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b8555"; "too long")]
+    #[test_case("g3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"; "not hex")]
+    #[test_case("sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"; "prefixed")]
+    fn sha256_digest_invalid(s: &str) {
+        assert!(parse_sha256_digest(s).is_err(), "{s}");
+    }
+
+    #[test]
+    fn qcow2_backing_file() {
+        let path = env::temp_dir().join(format!("crun-vm-test-{}", uuid::Uuid::new_v4()));
+
+        let mut header = b"QFI\xfb\0\0\0\x03".to_vec();
+        header.extend(32u64.to_be_bytes());
+        header.extend(11u32.to_be_bytes());
+        header.resize(32, 0);
+        header.extend(b"base.qcow2x");
+
+        fs::write(&path, &header).unwrap();
+        assert_eq!(
+            get_qcow2_backing_file(&path).unwrap().as_deref(),
+            Some("base.qcow2x")
+        );
+
+        header[8..16].copy_from_slice(&0u64.to_be_bytes());
+        fs::write(&path, &header).unwrap();
+        assert_eq!(get_qcow2_backing_file(&path).unwrap(), None);
+
+        fs::write(&path, b"not a qcow2 image, but long enough").unwrap();
+        assert_eq!(get_qcow2_backing_file(&path).unwrap(), None);
+
+        fs::write(&path, b"QFI").unwrap();
+        assert_eq!(get_qcow2_backing_file(&path).unwrap(), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// File metadata as seen through a different mount of the same file system.
+    struct RemountedMetadata(fs::Metadata);
+
+    impl MetadataExt for RemountedMetadata {
+        fn dev(&self) -> u64 {
+            self.0.dev() + 1
+        }
+        fn ino(&self) -> u64 {
+            self.0.ino()
+        }
+        fn mode(&self) -> u32 {
+            self.0.mode()
+        }
+        fn nlink(&self) -> u64 {
+            self.0.nlink()
+        }
+        fn uid(&self) -> u32 {
+            self.0.uid()
+        }
+        fn gid(&self) -> u32 {
+            self.0.gid()
+        }
+        fn rdev(&self) -> u64 {
+            self.0.rdev()
+        }
+        fn size(&self) -> u64 {
+            self.0.size()
+        }
+        fn atime(&self) -> i64 {
+            self.0.atime()
+        }
+        fn atime_nsec(&self) -> i64 {
+            self.0.atime_nsec()
+        }
+        fn mtime(&self) -> i64 {
+            self.0.mtime()
+        }
+        fn mtime_nsec(&self) -> i64 {
+            self.0.mtime_nsec()
+        }
+        fn ctime(&self) -> i64 {
+            self.0.ctime()
+        }
+        fn ctime_nsec(&self) -> i64 {
+            self.0.ctime_nsec()
+        }
+        fn blksize(&self) -> u64 {
+            self.0.blksize()
+        }
+        fn blocks(&self) -> u64 {
+            self.0.blocks()
+        }
+    }
+
+    #[test]
+    fn file_digest_cache_hits_across_mounts() {
+        let dir_path = env::temp_dir().join(format!("crun-vm-test-{}", uuid::Uuid::new_v4()));
+        let file_path = dir_path.join("image");
+        fs::create_dir_all(&dir_path).unwrap();
+        fs::write(&file_path, "").unwrap();
+
+        let digest = get_file_digest(&file_path, &dir_path).unwrap();
+        assert_eq!(
+            digest,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        // the same file seen through another mount maps to the same entry, which is then used
+        // instead of hashing the file again
+        let remounted = RemountedMetadata(file_path.metadata().unwrap());
+        let cache_path = dir_path.join("digests").join(digest_cache_key(&remounted));
+        assert!(cache_path.is_file());
+
+        fs::write(&cache_path, "cached").unwrap();
+        assert_eq!(get_file_digest(&file_path, &dir_path).unwrap(), "cached");
+
+        // a modified file doesn't
+        fs::write(&file_path, "x").unwrap();
+        assert_ne!(get_file_digest(&file_path, &dir_path).unwrap(), "cached");
+
+        fs::remove_dir_all(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn evict_cache_entries_keeps_locked_and_recent() {
+        let cache_dir = env::temp_dir().join(format!("crun-vm-test-{}", uuid::Uuid::new_v4()));
+
+        let create_entry = |name: &str, age: Duration| {
+            let entry_path = cache_dir.join("decompressed").join(name);
+            fs::create_dir_all(&entry_path).unwrap();
+            fs::write(entry_path.join("image"), vec![1; 1 << 16]).unwrap();
+
+            let lock = lock_cache_entry(&entry_path).unwrap();
+            let last_used =
+                TimeSpec::from(SystemTime::now().duration_since(UNIX_EPOCH).unwrap() - age);
+            futimens(lock._file.as_raw_fd(), &last_used, &last_used).unwrap();
+
+            (entry_path, lock)
+        };
+
+        let old = Duration::from_secs(2 * 60 * 60);
+        let (unlocked_path, _) = create_entry("unlocked", old);
+        let (locked_path, _lock) = create_entry("locked", old);
+        let (recent_path, _) = create_entry("recent", Duration::ZERO);
+
+        evict_cache_entries(&cache_dir, 0).unwrap();
+
+        assert!(!unlocked_path.exists());
+        assert!(!unlocked_path.with_extension("lock").exists());
+        assert!(locked_path.exists());
+        assert!(recent_path.exists());
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
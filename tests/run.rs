@@ -49,7 +49,8 @@ fn complex_test_case(
         run_args: vec![
             "-h=my-test-vm".to_string(),
             format!("-v=./util:{home_dir}/util"),
-            format!("-v=./README.md:{home_dir}/README.md:z,ro"), // "ro" is so qemu uses shared lock
+            format!("-v=./examples:{home_dir}/examples:ro"),
+            format!("-v=./README.md:{home_dir}/README.md:z,ro"),
             format!("--mount=type=tmpfs,dst={home_dir}/tmp"),
             image.to_string(),
             format!("--cloud-init={cloud_init_and_ignition_prefix}examples/cloud-init/config"),
@@ -59,9 +60,12 @@ fn complex_test_case(
         test_script: format!(
             "
             mount -l | grep '^virtiofs-0 on {home_dir}/util type virtiofs'
+            mount -l | grep '^virtiofs-1 on {home_dir}/examples type virtiofs (ro[,)]'
+            ! touch {home_dir}/examples/test
             mount -l | grep '^tmpfs on {home_dir}/tmp type tmpfs'
-            [[ -b ~/README.md ]]
-            sudo grep 'This project is released under' ~/README.md
+            [[ -f ~/README.md ]]
+            grep 'This project is released under' ~/README.md
+            ! sudo touch ~/README.md
             "
         ),
     }
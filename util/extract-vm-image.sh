@@ -3,14 +3,19 @@
 
 set -o errexit -o pipefail -o nounset
 
-if (( $# != 2 )); then
-    >&2 echo "Usage: $0 <container_image_tag> <output_vm_image_file>"
-    >&2 echo "Extract a VM image file from a container image."
+if (( $# != 2 && $# != 3 )); then
+    >&2 echo "Usage: $0 <container_image_tag> <output_path> [<vm_image_file>]"
+    >&2 echo "Extract VM image files from a container image."
+    >&2 echo "If <vm_image_file> is given, as a path in the container image or a file name,"
+    >&2 echo "only that file is extracted, to <output_path>. Otherwise, if the container image"
+    >&2 echo "has a single VM image file, it is extracted to <output_path>, and if it has"
+    >&2 echo "several, they are all extracted into directory <output_path>."
     exit 2
 fi
 
 container_image_tag=$1
-output_vm_image_file=$2
+output_path=$2
+vm_image_file=${3:-}
 
 temp_dir=$( mktemp -d )
 trap 'rm -fr "${temp_dir}"' EXIT
@@ -19,17 +24,45 @@ container_id=$( podman container create --quiet "${container_image_tag}" "" )
 trap 'podman container rm "${container_id}" >/dev/null; rm -fr "${temp_dir}"' EXIT
 
 podman container export -o "${temp_dir}/root.tar" "${container_id}"
-mapfile -t candidates < <( tar -tf "${temp_dir}/root.tar" | grep -xP '[^/]+|disk/[^/]+' )
+mapfile -t candidates < <(
+    tar -tf "${temp_dir}/root.tar" |
+    grep -xP '[^/]+|disk/[^/]+' |
+    grep -vxP '(disk/)?crun-vm\.(yaml|yml|json)' |
+    grep -vixP '(disk/)?(readme.*|checksum.*|.*sums|.*\.(md5|sha1|sha256|sha512)(sum)?)' || true
+    )
 
 if (( ${#candidates[@]} == 0 )); then
     >&2 echo "Error: found no VM image file in the container image"
     exit 1
-elif (( ${#candidates[@]} > 1 )); then
-    >&2 echo "Error: found more than one VM image file in the container image"
-    exit 1
 fi
 
-tar -C "${temp_dir}" -xf "${temp_dir}/root.tar" "${candidates[0]}"
-chmod +w "${temp_dir}/${candidates[0]}"
+if [[ -n "${vm_image_file}" ]]; then
+    selected=()
+    for candidate in "${candidates[@]}"; do
+        if [[ "/${candidate}" == "${vm_image_file}" || "${candidate}" == "${vm_image_file}" ||
+            "${candidate##*/}" == "${vm_image_file}" ]]; then
+            selected+=( "${candidate}" )
+        fi
+    done
+
+    if (( ${#selected[@]} != 1 )); then
+        >&2 echo "Error: ${vm_image_file} is not one of the VM image files in the container image:"
+        >&2 printf '  /%s\n' "${candidates[@]}"
+        exit 1
+    fi
+
+    candidates=( "${selected[@]}" )
+fi
+
+tar -C "${temp_dir}" -xf "${temp_dir}/root.tar" "${candidates[@]}"
 
-mv -f "${temp_dir}/${candidates[0]}" "${output_vm_image_file}"
+if (( ${#candidates[@]} == 1 )); then
+    chmod +w "${temp_dir}/${candidates[0]}"
+    mv -f "${temp_dir}/${candidates[0]}" "${output_path}"
+else
+    mkdir -p "${output_path}"
+    for candidate in "${candidates[@]}"; do
+        chmod +w "${temp_dir}/${candidate}"
+        mv -f "${temp_dir}/${candidate}" "${output_path}/"
+    done
+fi